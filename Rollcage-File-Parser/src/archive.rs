@@ -0,0 +1,298 @@
+use std::{borrow::Cow, fmt, fs::File, io::{self, Read}, path::Path};
+
+use memmap2::Mmap;
+
+use crate::{
+  error::Error,
+  file_formats::{
+    gt::{decompress, parse_header, GtDecoder},
+    identify_format,
+    idx::{parse_records, IdxRecord},
+    Format,
+    MAX_SIGNATURE_LENGTH
+  }
+};
+
+// An img file mapped into memory. Its bytes are only read from disk when an entry uses them, so
+// listing an archive or reading a single record does not read the whole file.
+#[derive(Debug)]
+pub struct ImgFile {
+  map: Mmap
+}
+
+impl ImgFile {
+  pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    let file = File::open(path)?;
+    // The mapping is only read from. Like any mapped file, it must not be truncated by another
+    // process while it is open.
+    let map = unsafe { Mmap::map(&file)? };
+    Ok(Self { map })
+  }
+
+  pub fn bytes(&self) -> &[u8] {
+    &self.map
+  }
+}
+
+// An idx file paired with the img file its records point into.
+#[derive(Debug, Clone)]
+pub struct Archive<'a> {
+  records: Vec<IdxRecord>,
+  img: &'a [u8]
+}
+
+impl<'a> Archive<'a> {
+  pub fn open(idx: &[u8], img: &'a [u8]) -> Result<Self, Error> {
+    let records = parse_records(idx)?;
+    Ok(Self { records, img })
+  }
+
+  pub fn len(&self) -> usize {
+    self.records.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.records.is_empty()
+  }
+
+  pub fn records(&self) -> &[IdxRecord] {
+    &self.records
+  }
+
+  pub fn entries(&self) -> impl Iterator<Item = Entry<'a>> + '_ {
+    (0..self.records.len()).filter_map(move |id| self.get(id))
+  }
+
+  pub fn get(&self, id: usize) -> Option<Entry<'a>> {
+    let record = *self.records.get(id)?;
+    let start = record.file_offset as usize;
+    let end = start.checked_add(record.compressed_file_length as usize);
+    let data = end.and_then(|end| self.img.get(start..end));
+    Some(Entry { id, record, data })
+  }
+
+  // Checks the records against each other and against the img: their bounds, how they are laid
+  // out in the img and whether their lengths agree with their data. The data is not decoded.
+  pub fn check_records(&self) -> Vec<Issue> {
+    let mut issues = Vec::new();
+
+    for entry in self.entries() {
+      let record = entry.record;
+      let data = match entry.data {
+        Some(data) => data,
+        None => {
+          let end = record.file_offset as u64 + record.compressed_file_length as u64;
+          issues.push(Issue::OutOfBounds { entry: entry.id, end, img_length: self.img.len() as u64 });
+          continue;
+        }
+      };
+
+      match identify_format(data) {
+        Format::GT20 => if let Ok(header) = parse_header(data) {
+          if header.gt_uncompressed_size != record.decompressed_file_length {
+            issues.push(Issue::SizeMismatch { entry: entry.id, header_size: header.gt_uncompressed_size, decompressed_file_length: record.decompressed_file_length });
+          }
+        },
+        _ => if record.compressed_file_length != record.decompressed_file_length {
+          issues.push(Issue::LengthMismatch { entry: entry.id, compressed_file_length: record.compressed_file_length, decompressed_file_length: record.decompressed_file_length });
+        }
+      }
+    }
+
+    // Walk the records in img order. Each one should start where the previous one ends.
+    let mut order: Vec<usize> = (0..self.records.len()).collect();
+    order.sort_by_key(|&id| (self.records[id].file_offset, self.records[id].compressed_file_length));
+    let mut previous: Option<(usize, u64)> = None; // The record reaching furthest so far and its end
+    for id in order {
+      let record = &self.records[id];
+      let start = record.file_offset as u64;
+      let end = start + record.compressed_file_length as u64;
+      let previous_end = previous.map_or(0, |(_, end)| end);
+      if start < previous_end && start < end {
+        let (other, _) = previous.unwrap();
+        issues.push(Issue::Overlap { entry: id, other, length: previous_end.min(end) - start });
+      } else if start > previous_end {
+        issues.push(Issue::Gap { entry: id, previous: previous.map(|(id, _)| id), length: start - previous_end });
+      }
+      if end > previous_end {
+        previous = Some((id, end));
+      }
+    }
+
+    issues.sort_by_key(Issue::entry);
+    issues
+  }
+}
+
+// The decoder's io errors wrap the crate error that caused them.
+fn gt_error(error: io::Error, length: usize) -> Error {
+  error.into_inner().and_then(|error| error.downcast::<Error>().ok())
+    .map_or_else(|| Error::truncated(Format::GT20, length), |error| *error)
+}
+
+// A problem with an archive record, found when verifying an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Issue {
+  // The record reaches past the end of the img. `end` is its offset plus its compressed length.
+  OutOfBounds { entry: usize, end: u64, img_length: u64 },
+  // The record starts inside `other`, the record before it in the img.
+  Overlap { entry: usize, other: usize, length: u64 },
+  // Unused bytes between the end of `previous` (or the start of the img) and the record.
+  Gap { entry: usize, previous: Option<usize>, length: u64 },
+  // The GT20 header gives a different uncompressed size than the record.
+  SizeMismatch { entry: usize, header_size: u32, decompressed_file_length: u32 },
+  // The record is stored uncompressed but its two lengths differ.
+  LengthMismatch { entry: usize, compressed_file_length: u32, decompressed_file_length: u32 },
+  // The record's data could not be decompressed or parsed.
+  Invalid { entry: usize, error: Error },
+  // The record decompresses, but not in place with the overlap its GT20 header gives, the way the
+  // game is thought to load it.
+  InPlaceFailure { entry: usize, error: Error }
+}
+
+impl Issue {
+  pub fn entry(&self) -> usize {
+    match self {
+      Issue::OutOfBounds { entry, .. }
+      | Issue::Overlap { entry, .. }
+      | Issue::Gap { entry, .. }
+      | Issue::SizeMismatch { entry, .. }
+      | Issue::LengthMismatch { entry, .. }
+      | Issue::Invalid { entry, .. }
+      | Issue::InPlaceFailure { entry, .. } => *entry
+    }
+  }
+
+  // Gaps are reported but do not stop the game from loading the archive. How the game uses the
+  // GT20 overlap is not confirmed, so in place decompression failures are only warnings too.
+  pub fn is_error(&self) -> bool {
+    !matches!(self, Issue::Gap { .. } | Issue::InPlaceFailure { .. })
+  }
+}
+
+impl fmt::Display for Issue {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Issue::OutOfBounds { entry, end, img_length } => write!(f, "record {}: ends at byte {:#x}, past the end of the img at {:#x}", entry, end, img_length),
+      Issue::Overlap { entry, other, length } => write!(f, "record {}: overlaps record {} by {:#x} bytes", entry, other, length),
+      Issue::Gap { entry, previous: Some(previous), length } => write!(f, "record {}: starts {:#x} bytes after the end of record {}", entry, length, previous),
+      Issue::Gap { entry, previous: None, length } => write!(f, "record {}: starts {:#x} bytes after the start of the img", entry, length),
+      Issue::SizeMismatch { entry, header_size, decompressed_file_length } => write!(f, "record {}: gt20 header gives an uncompressed size of {:#x}, the record gives {:#x}", entry, header_size, decompressed_file_length),
+      Issue::LengthMismatch { entry, compressed_file_length, decompressed_file_length } => write!(f, "record {}: is not compressed but its compressed length {:#x} differs from its decompressed length {:#x}", entry, compressed_file_length, decompressed_file_length),
+      Issue::Invalid { entry, error } => write!(f, "{}", error.clone().with_entry(*entry)),
+      Issue::InPlaceFailure { entry, error } => write!(f, "record {}: does not decompress in place: {}", entry, error)
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<'a> {
+  pub id: usize,
+  pub record: IdxRecord,
+  data: Option<&'a [u8]> // None when the record points outside of the img
+}
+
+impl<'a> Entry<'a> {
+  pub fn raw_bytes(&self) -> Result<&'a [u8], Error> {
+    self.data.ok_or(Error::RecordOutOfBounds { entry: Some(self.id), format: Format::Unknown, offset: self.record.file_offset as usize })
+  }
+
+  // Format of the stored bytes, before any decompression.
+  pub fn format(&self) -> Format {
+    match self.data {
+      Some(data) => identify_format(data),
+      None => Format::Unknown
+    }
+  }
+
+  // The entry's contents, GT20 decompressed if it is stored compressed.
+  pub fn decoded_bytes(&self) -> Result<Cow<'a, [u8]>, Error> {
+    let data = self.raw_bytes()?;
+    match identify_format(data) {
+      Format::GT20 => match decompress(data, self.record.decompressed_file_length) {
+        Ok(uncompressed_data) => Ok(Cow::Owned(uncompressed_data)),
+        Err(error) => Err(error.with_entry(self.id))
+      },
+      _ => Ok(Cow::Borrowed(data))
+    }
+  }
+
+  // Format of the entry's contents, after decompression. Only the start of a GT20 entry is
+  // decompressed to find it, so corrupt data further on is not noticed.
+  pub fn decoded_format(&self) -> Result<Format, Error> {
+    let length = self.raw_bytes()?.len();
+    let mut start = Vec::with_capacity(MAX_SIGNATURE_LENGTH);
+    self.reader()?.take(MAX_SIGNATURE_LENGTH as u64).read_to_end(&mut start)
+      .map_err(|error| gt_error(error, length).with_entry(self.id))?;
+    Ok(identify_format(&start))
+  }
+
+  // The entry's contents as a reader, GT20 decompressed as they are read if stored compressed.
+  pub fn reader(&self) -> Result<Box<dyn Read + 'a>, Error> {
+    let data = self.raw_bytes()?;
+    match identify_format(data) {
+      Format::GT20 => match GtDecoder::new(data) {
+        Ok(decoder) => Ok(Box::new(decoder)),
+        Err(error) => Err(gt_error(error, data.len()).with_entry(self.id))
+      },
+      _ => Ok(Box::new(data))
+    }
+  }
+}
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::file_formats::{gt::compress, idx::write_records};
+
+  fn record(file_offset: u32, compressed_file_length: u32, decompressed_file_length: u32) -> IdxRecord {
+    IdxRecord { file_offset, compressed_file_length, decompressed_file_length, unused: 0 }
+  }
+
+  fn check(records: &[IdxRecord], img: &[u8]) -> Vec<Issue> {
+    Archive::open(&write_records(records), img).unwrap().check_records()
+  }
+
+  #[test]
+  fn records_laid_out_back_to_back_have_no_issues() {
+    let records = [record(0, 4, 4), record(4, 0, 0), record(4, 8, 8)];
+    assert_eq!(check(&records, &[0; 12]), []);
+  }
+
+  #[test]
+  fn overlaps_and_gaps_are_found_in_img_order() {
+    // Record 0 is stored after record 1, and record 2 starts inside record 1. Record 3 starts
+    // after a gap, and record 4 lies entirely within record 3.
+    let records = [record(10, 6, 6), record(0, 10, 10), record(8, 2, 2), record(20, 8, 8), record(22, 2, 2)];
+    assert_eq!(check(&records, &[0; 28]), [
+      Issue::Overlap { entry: 2, other: 1, length: 2 },
+      Issue::Gap { entry: 3, previous: Some(0), length: 4 },
+      Issue::Overlap { entry: 4, other: 3, length: 2 }
+    ]);
+
+    let records = [record(3, 1, 1)];
+    assert_eq!(check(&records, &[0; 4]), [Issue::Gap { entry: 0, previous: None, length: 3 }]);
+  }
+
+  #[test]
+  fn empty_records_inside_others_are_not_overlaps() {
+    let records = [record(0, 8, 8), record(4, 0, 0)];
+    assert_eq!(check(&records, &[0; 8]), []);
+  }
+
+  #[test]
+  fn lengths_are_checked_against_the_data() {
+    let compressed = compress(&[5; 100]);
+    let mut img = compressed.clone();
+    img.extend_from_slice(&[0; 8]);
+    let records = [
+      record(0, compressed.len() as u32, 99),
+      record(compressed.len() as u32, 4, 5),
+      record(compressed.len() as u32 + 4, 8, 8)
+    ];
+    assert_eq!(check(&records, &img), [
+      Issue::SizeMismatch { entry: 0, header_size: 100, decompressed_file_length: 99 },
+      Issue::LengthMismatch { entry: 1, compressed_file_length: 4, decompressed_file_length: 5 },
+      Issue::OutOfBounds { entry: 2, end: img.len() as u64 + 4, img_length: img.len() as u64 }
+    ]);
+  }
+}
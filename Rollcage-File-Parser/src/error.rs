@@ -0,0 +1,151 @@
+use std::fmt;
+
+use nom::{error::{ErrorKind, ParseError}, IResult};
+
+use crate::file_formats::Format;
+
+// Every error records the archive entry it happened in (when known), the format being read
+// and the byte offset of the failure within that entry's data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+  // The data does not start with the signature of its format.
+  InvalidSignature { entry: Option<usize>, format: Format, offset: usize },
+  // The data ends in the middle of a structure.
+  Truncated { entry: Option<usize>, format: Format, offset: usize },
+  // An offset stored in the data points past its end. `offset` is the stored offset.
+  OffsetOutOfRange { entry: Option<usize>, format: Format, offset: usize },
+  // A value in the data is inconsistent with the rest of it.
+  InvalidData { entry: Option<usize>, format: Format, offset: usize, reason: &'static str },
+  // The idx record points outside of the img file. `offset` is the record's file offset.
+  RecordOutOfBounds { entry: Option<usize>, format: Format, offset: usize }
+}
+
+impl Error {
+  pub(crate) fn truncated(format: Format, offset: usize) -> Self {
+    Error::Truncated { entry: None, format, offset }
+  }
+
+  pub(crate) fn invalid_data(format: Format, offset: usize, reason: &'static str) -> Self {
+    Error::InvalidData { entry: None, format, offset, reason }
+  }
+
+  pub fn entry(&self) -> Option<usize> {
+    match self {
+      Error::InvalidSignature { entry, .. }
+      | Error::Truncated { entry, .. }
+      | Error::OffsetOutOfRange { entry, .. }
+      | Error::InvalidData { entry, .. }
+      | Error::RecordOutOfBounds { entry, .. } => *entry
+    }
+  }
+
+  pub fn format(&self) -> Format {
+    match self {
+      Error::InvalidSignature { format, .. }
+      | Error::Truncated { format, .. }
+      | Error::OffsetOutOfRange { format, .. }
+      | Error::InvalidData { format, .. }
+      | Error::RecordOutOfBounds { format, .. } => *format
+    }
+  }
+
+  pub fn offset(&self) -> usize {
+    match self {
+      Error::InvalidSignature { offset, .. }
+      | Error::Truncated { offset, .. }
+      | Error::OffsetOutOfRange { offset, .. }
+      | Error::InvalidData { offset, .. }
+      | Error::RecordOutOfBounds { offset, .. } => *offset
+    }
+  }
+
+  // Attributes the error to an archive entry.
+  pub fn with_entry(mut self, id: usize) -> Self {
+    match &mut self {
+      Error::InvalidSignature { entry, .. }
+      | Error::Truncated { entry, .. }
+      | Error::OffsetOutOfRange { entry, .. }
+      | Error::InvalidData { entry, .. }
+      | Error::RecordOutOfBounds { entry, .. } => *entry = Some(id)
+    }
+    self
+  }
+
+  // Converts a failure of the internal nom parsers, which ran on slices of `base`.
+  pub(crate) fn from_parse(base: &[u8], format: Format, error: nom::Err<ParseFailure<'_>>) -> Self {
+    let failure = match error {
+      nom::Err::Incomplete(_) => return Error::truncated(format, base.len()),
+      nom::Err::Error(failure) | nom::Err::Failure(failure) => failure
+    };
+    let offset = (failure.input.as_ptr() as usize).saturating_sub(base.as_ptr() as usize);
+    match failure.kind {
+      FailureKind::Signature => Error::InvalidSignature { entry: None, format, offset },
+      FailureKind::Truncated => Error::truncated(format, offset),
+      FailureKind::OffsetOutOfRange(offset) => Error::OffsetOutOfRange { entry: None, format, offset },
+      FailureKind::InvalidData(reason) => Error::invalid_data(format, offset, reason)
+    }
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if let Some(entry) = self.entry() {
+      write!(f, "record {}: ", entry)?;
+    }
+    let format = match self.format() {
+      Format::Unknown => String::from("data"),
+      format => format!("{} data", format)
+    };
+    match self {
+      Error::InvalidSignature { offset, .. } => write!(f, "{} has an invalid signature at byte {:#x}", format, offset),
+      Error::Truncated { offset, .. } => write!(f, "{} is truncated at byte {:#x}", format, offset),
+      Error::OffsetOutOfRange { offset, .. } => write!(f, "{} refers to byte {:#x}, past its end", format, offset),
+      Error::InvalidData { offset, reason, .. } => write!(f, "{} is invalid at byte {:#x}: {}", format, offset, reason),
+      Error::RecordOutOfBounds { offset, .. } => write!(f, "{} starting at byte {:#x} of the img lies outside of it", format, offset)
+    }
+  }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Debug, Clone, Copy)]
+pub enum FailureKind {
+  Signature,
+  Truncated,
+  OffsetOutOfRange(usize),
+  InvalidData(&'static str)
+}
+
+// Error type of the nom parsers. Keeps the input the failure happened at, so the
+// byte offset can be worked out against the start of the data.
+#[derive(Debug, Clone, Copy)]
+pub struct ParseFailure<'a> {
+  pub input: &'a [u8],
+  pub kind: FailureKind
+}
+
+impl<'a> ParseError<&'a [u8]> for ParseFailure<'a> {
+  fn from_error_kind(input: &'a [u8], kind: ErrorKind) -> Self {
+    let kind = match kind {
+      ErrorKind::Tag => FailureKind::Signature,
+      _ => FailureKind::Truncated
+    };
+    Self { input, kind }
+  }
+
+  fn append(_input: &'a [u8], _kind: ErrorKind, other: Self) -> Self {
+    other
+  }
+}
+
+pub type ParseResult<'a, O> = IResult<&'a [u8], O, ParseFailure<'a>>;
+
+// Fails with an `Error::InvalidData` at `input`.
+pub(crate) fn invalid_data<'a>(input: &'a [u8], reason: &'static str) -> nom::Err<ParseFailure<'a>> {
+  nom::Err::Failure(ParseFailure { input, kind: FailureKind::InvalidData(reason) })
+}
+
+// Slices `input` from `offset`, an offset read from the data itself.
+pub(crate) fn seek(input: &[u8], offset: usize) -> Result<&[u8], nom::Err<ParseFailure<'_>>> {
+  input.get(offset ..).ok_or(nom::Err::Error(ParseFailure { input: &input[input.len() ..], kind: FailureKind::OffsetOutOfRange(offset) }))
+}
\ No newline at end of file
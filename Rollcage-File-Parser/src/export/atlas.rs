@@ -0,0 +1,179 @@
+use image::{imageops, Rgba, RgbaImage};
+use serde_json::json;
+
+use crate::file_formats::btp::Texture;
+
+const PADDING: u32 = 4;
+// Rows of textures are wrapped at this width, unless a texture is wider.
+const MAX_ROW_WIDTH: u32 = 1024;
+const BACKGROUND: Rgba<u8> = Rgba([48, 48, 48, 255]);
+const LABEL_COLOUR: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+// Labels are drawn with a 3x5 pixel font, scaled up.
+const GLYPH_WIDTH: u32 = 3;
+const GLYPH_HEIGHT: u32 = 5;
+const GLYPH_SCALE: u32 = 2;
+const GLYPH_ADVANCE: u32 = (GLYPH_WIDTH + 1) * GLYPH_SCALE;
+const LABEL_HEIGHT: u32 = GLYPH_HEIGHT * GLYPH_SCALE + PADDING;
+
+// Rows of each glyph from the top, the leftmost pixel in the highest of the three bits.
+fn glyph(character: char) -> [u8; GLYPH_HEIGHT as usize] {
+  match character {
+    '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+    '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+    '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+    '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+    '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+    '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+    '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+    '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+    '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+    '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+    'x' => [0b000, 0b101, 0b010, 0b101, 0b000],
+    'p' => [0b000, 0b111, 0b101, 0b111, 0b100],
+    '#' => [0b101, 0b111, 0b101, 0b111, 0b101],
+    _ => [0; GLYPH_HEIGHT as usize]
+  }
+}
+
+fn label_width(label: &str) -> u32 {
+  label.chars().count() as u32 * GLYPH_ADVANCE
+}
+
+fn draw_label(atlas: &mut RgbaImage, label: &str, x: u32, y: u32) {
+  for (i, character) in label.chars().enumerate() {
+    let glyph_x = x + i as u32 * GLYPH_ADVANCE;
+    for (row, bits) in glyph(character).iter().enumerate() {
+      for column in 0 .. GLYPH_WIDTH {
+        if bits & (0b100 >> column) == 0 {
+          continue;
+        }
+        for dy in 0 .. GLYPH_SCALE {
+          for dx in 0 .. GLYPH_SCALE {
+            atlas.put_pixel(glyph_x + column * GLYPH_SCALE + dx, y + row as u32 * GLYPH_SCALE + dy, LABEL_COLOUR);
+          }
+        }
+      }
+    }
+  }
+}
+
+// Where a texture was placed in the atlas. `image` is the texture's image_N number and
+// `texture_page` its index in the texture page table.
+#[derive(Debug, Clone, Copy)]
+pub struct AtlasRect {
+  pub image: usize,
+  pub texture_page: usize,
+  pub palette: u32,
+  pub x: u32,
+  pub y: u32,
+  pub width: u32,
+  pub height: u32
+}
+
+// Packs every texture of a btp into one image, in rows and in texture page order, each under a
+// label of its image number, size and palette ("#3 64x32 p1").
+pub fn build_atlas(texture_pages: &[Option<Texture>]) -> (RgbaImage, Vec<AtlasRect>) {
+  let textures: Vec<(usize, &Texture, String)> = texture_pages.iter().enumerate()
+    .filter_map(|(page, texture)| texture.as_ref().map(|texture| (page, texture)))
+    .enumerate()
+    .map(|(image, (page, texture))| (page, texture, format!("#{} {}x{} p{}", image, texture.info.width, texture.info.height, texture.info.palette)))
+    .collect();
+
+  // Lay the cells out in rows first, to know the size of the atlas.
+  let mut rects = Vec::with_capacity(textures.len());
+  let mut labels = Vec::with_capacity(textures.len());
+  let (mut x, mut y, mut row_height, mut atlas_width) = (PADDING, PADDING, 0, 0);
+  for (image, (page, texture, label)) in textures.iter().enumerate() {
+    let (width, height) = (texture.info.width as u32, texture.info.height as u32);
+    let cell_width = width.max(label_width(label));
+    let cell_height = LABEL_HEIGHT + height;
+    if x > PADDING && x + cell_width + PADDING > MAX_ROW_WIDTH {
+      x = PADDING;
+      y += row_height + PADDING;
+      row_height = 0;
+    }
+    labels.push((label.as_str(), x, y));
+    rects.push(AtlasRect { image, texture_page: *page, palette: texture.info.palette, x, y: y + LABEL_HEIGHT, width, height });
+    x += cell_width + PADDING;
+    row_height = row_height.max(cell_height);
+    atlas_width = atlas_width.max(x);
+  }
+  let atlas_height = if rects.is_empty() { PADDING } else { y + row_height + PADDING };
+
+  let mut atlas = RgbaImage::from_pixel(atlas_width.max(PADDING), atlas_height, BACKGROUND);
+  for ((label, label_x, label_y), ((_, texture, _), rect)) in labels.iter().zip(textures.iter().zip(rects.iter())) {
+    draw_label(&mut atlas, label, *label_x, *label_y);
+    imageops::replace(&mut atlas, &texture.to_rgba_image(), rect.x, rect.y);
+  }
+  (atlas, rects)
+}
+
+// Sidecar for the atlas image with the rectangle of every texture in it.
+pub fn atlas_to_json(atlas: &RgbaImage, rects: &[AtlasRect]) -> String {
+  let textures: Vec<_> = rects.iter().map(|rect| json!({
+    "image": rect.image,
+    "texture_page": rect.texture_page,
+    "palette": rect.palette,
+    "x": rect.x,
+    "y": rect.y,
+    "width": rect.width,
+    "height": rect.height
+  })).collect();
+  serde_json::to_string_pretty(&json!({
+    "width": atlas.width(),
+    "height": atlas.height(),
+    "textures": textures
+  })).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::Value;
+
+  use super::*;
+  use crate::file_formats::btp::{Colour, Palette, TexturePageInfo};
+
+  fn texture(width: u16, height: u16, palette: u32) -> Texture {
+    let mut data = [Colour { red: 0, green: 0, blue: 0, alpha: 255 }; 256];
+    for (i, colour) in data.iter_mut().enumerate() {
+      *colour = Colour { red: i as u8, green: palette as u8, blue: 200, alpha: 255 };
+    }
+    let info = TexturePageInfo { width, height, palette, texture_offset: 0 };
+    let image_data = (0 .. width as usize * height as usize).map(|i| (i % 251) as u8).collect();
+    Texture::new(info, Palette { data }, image_data).unwrap()
+  }
+
+  #[test]
+  fn textures_are_copied_into_rows_that_wrap() {
+    let pages = vec![Some(texture(600, 10, 0)), None, Some(texture(600, 20, 1)), Some(texture(8, 8, 2))];
+    let (atlas, rects) = build_atlas(&pages);
+
+    assert_eq!(rects.iter().map(|rect| (rect.image, rect.texture_page, rect.palette)).collect::<Vec<_>>(), vec![(0, 0, 0), (1, 2, 1), (2, 3, 2)]);
+    assert_eq!((rects[0].x, rects[0].y), (PADDING, PADDING + LABEL_HEIGHT));
+    assert_eq!(rects[1].x, PADDING);
+    assert!(rects[1].y >= rects[0].y + rects[0].height + PADDING + LABEL_HEIGHT);
+    assert_eq!(rects[2].y, rects[1].y);
+    assert!(rects[2].x >= rects[1].x + rects[1].width + PADDING);
+
+    for (rect, texture) in rects.iter().zip(pages.iter().flatten()) {
+      assert!(rect.x + rect.width <= atlas.width() && rect.y + rect.height <= atlas.height());
+      let image = texture.to_rgba_image();
+      for (x, y) in [(0, 0), (rect.width - 1, rect.height - 1), (rect.width / 2, rect.height / 3)].iter() {
+        assert_eq!(atlas.get_pixel(rect.x + x, rect.y + y), image.get_pixel(*x, *y));
+      }
+    }
+  }
+
+  #[test]
+  fn json_lists_the_atlas_size_and_every_rect() {
+    let (atlas, rects) = build_atlas(&[None, Some(texture(4, 6, 3))]);
+    let json: Value = serde_json::from_str(&atlas_to_json(&atlas, &rects)).unwrap();
+    assert_eq!((json["width"].as_u64(), json["height"].as_u64()), (Some(atlas.width() as u64), Some(atlas.height() as u64)));
+    assert_eq!(json["textures"], json!([{ "image": 0, "texture_page": 1, "palette": 3, "x": rects[0].x, "y": rects[0].y, "width": 4, "height": 6 }]));
+
+    let (atlas, rects) = build_atlas(&[None]);
+    assert!(rects.is_empty());
+    assert_eq!(atlas.dimensions(), (PADDING, PADDING));
+  }
+}
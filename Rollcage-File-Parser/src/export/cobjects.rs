@@ -0,0 +1,41 @@
+use serde_json::json;
+
+use crate::file_formats::btp::CObject;
+
+// Half the size of the marker drawn at each cobject in the debug model.
+const MARKER_SIZE: i64 = 64;
+
+// JSON dump of a btp's cobjects, with each one's position and its unknown word.
+pub fn cobjects_to_json(cobjects: &[CObject]) -> String {
+  let entries: Vec<_> = cobjects.iter().enumerate().map(|(index, cobject)| {
+    json!({
+      "index": index,
+      "position": [cobject.x, cobject.y, cobject.z],
+      "unknown_1": cobject.unknown_1
+    })
+  }).collect();
+
+  let dump = json!({
+    "num_cobjects": cobjects.len(),
+    "cobjects": entries
+  });
+  serde_json::to_string_pretty(&dump).unwrap()
+}
+
+// Debug Wavefront OBJ with one object per cobject, an octahedron centred on its position, so the
+// cobjects can be shown over the track models.
+pub fn cobjects_to_obj(cobjects: &[CObject]) -> String {
+  let mut obj = String::new();
+  for (index, cobject) in cobjects.iter().enumerate() {
+    let (x, y, z) = (cobject.x as i64, cobject.y as i64, cobject.z as i64);
+    obj.push_str(&format!("o cobject_{}\n", index));
+    for (dx, dy, dz) in [(MARKER_SIZE, 0, 0), (-MARKER_SIZE, 0, 0), (0, MARKER_SIZE, 0), (0, -MARKER_SIZE, 0), (0, 0, MARKER_SIZE), (0, 0, -MARKER_SIZE)] {
+      obj.push_str(&format!("v {} {} {}\n", x + dx, y + dy, z + dz));
+    }
+    let first = index * 6 + 1;
+    for (a, b, c) in [(0, 2, 4), (2, 1, 4), (1, 3, 4), (3, 0, 4), (2, 0, 5), (1, 2, 5), (3, 1, 5), (0, 3, 5)] {
+      obj.push_str(&format!("f {} {} {}\n", first + a, first + b, first + c));
+    }
+  }
+  obj
+}
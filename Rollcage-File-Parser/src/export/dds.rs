@@ -0,0 +1,77 @@
+use image::RgbaImage;
+
+const DDS_MAGIC: &[u8; 4] = b"DDS ";
+const DDS_HEADER_SIZE: u32 = 124;
+const DDS_PIXEL_FORMAT_SIZE: u32 = 32;
+
+// DDSD_CAPS | DDSD_HEIGHT | DDSD_WIDTH | DDSD_PITCH | DDSD_PIXELFORMAT
+const DDS_FLAGS: u32 = 0x1 | 0x2 | 0x4 | 0x8 | 0x1000;
+// DDPF_ALPHAPIXELS | DDPF_RGB
+const DDS_PIXEL_FORMAT_FLAGS: u32 = 0x1 | 0x40;
+const DDS_CAPS_TEXTURE: u32 = 0x1000;
+
+// Uncompressed 32 bit DDS (A8R8G8B8), which keeps the alpha of every pixel. The pixels are stored
+// in BGRA byte order, the same order btp palettes use.
+pub fn rgba_to_dds(image: &RgbaImage) -> Vec<u8> {
+  let mut header: Vec<u32> = vec![
+    DDS_HEADER_SIZE,
+    DDS_FLAGS,
+    image.height(),
+    image.width(),
+    image.width() * 4, // pitch
+    0, // depth
+    0 // mipmap count
+  ];
+  header.extend_from_slice(&[0; 11]); // reserved
+  header.extend_from_slice(&[
+    DDS_PIXEL_FORMAT_SIZE,
+    DDS_PIXEL_FORMAT_FLAGS,
+    0, // fourcc
+    32, // bits per pixel
+    0x00ff_0000, // red mask
+    0x0000_ff00, // green mask
+    0x0000_00ff, // blue mask
+    0xff00_0000 // alpha mask
+  ]);
+  header.extend_from_slice(&[DDS_CAPS_TEXTURE, 0, 0, 0, 0]);
+
+  let mut dds = Vec::with_capacity(4 + DDS_HEADER_SIZE as usize + image.as_raw().len());
+  dds.extend_from_slice(DDS_MAGIC);
+  dds.extend(header.iter().flat_map(|value| value.to_le_bytes()));
+  dds.extend(image.pixels().flat_map(|pixel| {
+    let [red, green, blue, alpha] = pixel.0;
+    [blue, green, red, alpha]
+  }));
+  dds
+}
+
+#[cfg(test)]
+mod tests {
+  use std::convert::TryInto;
+
+  use image::Rgba;
+
+  use super::*;
+
+  fn u32_at(dds: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(dds[offset .. offset + 4].try_into().unwrap())
+  }
+
+  #[test]
+  fn header_describes_the_image_and_pixels_are_bgra() {
+    let mut image = RgbaImage::new(2, 1);
+    image.put_pixel(0, 0, Rgba([1, 2, 3, 4]));
+    image.put_pixel(1, 0, Rgba([250, 251, 252, 0]));
+    let dds = rgba_to_dds(&image);
+
+    assert_eq!(dds.len(), 4 + DDS_HEADER_SIZE as usize + 8);
+    assert_eq!(&dds[.. 4], DDS_MAGIC);
+    assert_eq!(u32_at(&dds, 4), DDS_HEADER_SIZE);
+    assert_eq!((u32_at(&dds, 12), u32_at(&dds, 16), u32_at(&dds, 20)), (1, 2, 8));
+    assert_eq!(u32_at(&dds, 76), DDS_PIXEL_FORMAT_SIZE);
+    assert_eq!(u32_at(&dds, 88), 32);
+    assert_eq!(u32_at(&dds, 104), 0xff00_0000);
+    assert_eq!(u32_at(&dds, 108), DDS_CAPS_TEXTURE);
+    assert_eq!(&dds[128 ..], &[3, 2, 1, 4, 252, 251, 250, 0]);
+  }
+}
@@ -0,0 +1,263 @@
+use std::collections::BTreeMap;
+
+use image::{codecs::png::PngEncoder, ColorType, ImageError};
+use serde_json::{json, Value};
+
+use crate::file_formats::{btp::Texture, gfxm::Gfxm, modl::Modl};
+
+use super::{placed_modls, texture_coordinates};
+
+const GLB_MAGIC: &[u8; 4] = b"glTF";
+const GLB_VERSION: u32 = 2;
+const GLB_CHUNK_JSON: u32 = 0x4E4F534A; // "JSON"
+const GLB_CHUNK_BIN: u32 = 0x004E4942; // "BIN\0"
+
+const COMPONENT_FLOAT: u32 = 5126;
+const COMPONENT_UNSIGNED_INT: u32 = 5125;
+const TARGET_ARRAY_BUFFER: u32 = 34962;
+const TARGET_ELEMENT_ARRAY_BUFFER: u32 = 34963;
+const FILTER_NEAREST: u32 = 9728;
+
+// GLB chunks and buffer views start on four byte boundaries. `is_multiple_of` needs Rust 1.87,
+// which is newer than the crate asks for.
+#[allow(clippy::manual_is_multiple_of)]
+fn pad_to_four_bytes(data: &mut Vec<u8>, padding: u8) {
+  while data.len() % 4 != 0 {
+    data.push(padding);
+  }
+}
+
+// The binary buffer and the JSON arrays that index into it.
+#[derive(Default)]
+struct GltfBuilder {
+  buffer: Vec<u8>,
+  buffer_views: Vec<Value>,
+  accessors: Vec<Value>
+}
+
+impl GltfBuilder {
+  fn push_buffer_view(&mut self, data: &[u8], target: Option<u32>) -> usize {
+    pad_to_four_bytes(&mut self.buffer, 0);
+    let mut buffer_view = json!({
+      "buffer": 0,
+      "byteOffset": self.buffer.len(),
+      "byteLength": data.len()
+    });
+    if let Some(target) = target {
+      buffer_view["target"] = json!(target);
+    }
+    self.buffer.extend_from_slice(data);
+    self.buffer_views.push(buffer_view);
+    self.buffer_views.len() - 1
+  }
+
+  fn push_vec3(&mut self, values: &[[f32; 3]]) -> usize {
+    let data: Vec<u8> = values.iter().flatten().flat_map(|value| value.to_le_bytes()).collect();
+    let buffer_view = self.push_buffer_view(&data, Some(TARGET_ARRAY_BUFFER));
+
+    let mut min = [f32::MAX; 3];
+    let mut max = [f32::MIN; 3];
+    for value in values {
+      for axis in 0..3 {
+        min[axis] = min[axis].min(value[axis]);
+        max[axis] = max[axis].max(value[axis]);
+      }
+    }
+
+    self.accessors.push(json!({
+      "bufferView": buffer_view,
+      "componentType": COMPONENT_FLOAT,
+      "count": values.len(),
+      "type": "VEC3",
+      "min": min,
+      "max": max
+    }));
+    self.accessors.len() - 1
+  }
+
+  fn push_vec2(&mut self, values: &[[f32; 2]]) -> usize {
+    let data: Vec<u8> = values.iter().flatten().flat_map(|value| value.to_le_bytes()).collect();
+    let buffer_view = self.push_buffer_view(&data, Some(TARGET_ARRAY_BUFFER));
+    self.accessors.push(json!({
+      "bufferView": buffer_view,
+      "componentType": COMPONENT_FLOAT,
+      "count": values.len(),
+      "type": "VEC2"
+    }));
+    self.accessors.len() - 1
+  }
+
+  fn push_indices(&mut self, values: &[u32]) -> usize {
+    let data: Vec<u8> = values.iter().flat_map(|value| value.to_le_bytes()).collect();
+    let buffer_view = self.push_buffer_view(&data, Some(TARGET_ELEMENT_ARRAY_BUFFER));
+    self.accessors.push(json!({
+      "bufferView": buffer_view,
+      "componentType": COMPONENT_UNSIGNED_INT,
+      "count": values.len(),
+      "type": "SCALAR"
+    }));
+    self.accessors.len() - 1
+  }
+}
+
+// Vertex data of all faces of one MODL that share a texture page.
+#[derive(Default)]
+struct Primitive {
+  positions: Vec<[f32; 3]>,
+  uvs: Vec<[f32; 2]>,
+  indices: Vec<u32>
+}
+
+fn build_primitives(modl: &Modl, texture_pages: &[Option<Texture>]) -> BTreeMap<u16, Primitive> {
+  let mut primitives: BTreeMap<u16, Primitive> = BTreeMap::new();
+
+  for face in modl.faces.iter() {
+    let corners = &face.vertex_indices[.. face.num_vertices()];
+    if corners.iter().any(|index| *index as usize >= modl.vertices.len()) {
+      continue;
+    }
+
+    let primitive = primitives.entry(face.texture_page).or_default();
+    let first = primitive.positions.len() as u32;
+    for (corner, vertex_index) in corners.iter().enumerate() {
+      let vertex = modl.vertices[*vertex_index as usize];
+      primitive.positions.push([vertex.x as f32, vertex.y as f32, vertex.z as f32]);
+      primitive.uvs.push(texture_coordinates(texture_pages, face.texture_page, face.uvs[corner]));
+    }
+    for corner in 1 .. corners.len() as u32 - 1 {
+      primitive.indices.extend_from_slice(&[first, first + corner, first + corner + 1]);
+    }
+  }
+
+  primitives
+}
+
+// Adds the image, texture and material for a texture page and returns the material index.
+fn push_textured_material(builder: &mut GltfBuilder, images: &mut Vec<Value>, textures: &mut Vec<Value>, materials: &mut Vec<Value>, texture_page: u16, texture: &Texture) -> Result<usize, ImageError> {
+  let image = texture.to_rgba_image();
+  let mut png = Vec::new();
+  PngEncoder::new(&mut png).encode(&image, image.width(), image.height(), ColorType::Rgba8)?;
+
+  let buffer_view = builder.push_buffer_view(&png, None);
+  images.push(json!({ "bufferView": buffer_view, "mimeType": "image/png" }));
+  textures.push(json!({ "sampler": 0, "source": images.len() - 1 }));
+
+  let alpha_mode = if texture.palette.data.iter().all(|colour| colour.alpha == u8::MAX) {
+    "OPAQUE"
+  } else if texture.palette.data.iter().all(|colour| colour.alpha == 0 || colour.alpha == u8::MAX) {
+    "MASK"
+  } else {
+    "BLEND"
+  };
+  materials.push(json!({
+    "name": format!("texture_page_{}", texture_page),
+    "pbrMetallicRoughness": {
+      "baseColorTexture": { "index": textures.len() - 1 },
+      "metallicFactor": 0.0
+    },
+    "alphaMode": alpha_mode,
+    "doubleSided": true
+  }));
+  Ok(materials.len() - 1)
+}
+
+// Binary glTF of every placed MODL. `texture_pages` are the texture page table entries of the
+// BTP the faces' texture pages refer to, as returned by `btp::parse_texture_pages`.
+pub fn gfxm_to_glb(gfxm: &Gfxm, texture_pages: &[Option<Texture>]) -> Result<Vec<u8>, ImageError> {
+  let mut builder = GltfBuilder::default();
+  let mut images: Vec<Value> = Vec::new();
+  let mut textures: Vec<Value> = Vec::new();
+  let mut materials: Vec<Value> = Vec::new();
+  let mut material_indices: BTreeMap<u16, usize> = BTreeMap::new();
+  let mut meshes: Vec<Value> = Vec::new();
+  let mut nodes: Vec<Value> = Vec::new();
+
+  for placed in placed_modls(gfxm) {
+    let mut json_primitives: Vec<Value> = Vec::new();
+
+    for (texture_page, primitive) in build_primitives(placed.modl, texture_pages) {
+      let material = match material_indices.get(&texture_page) {
+        Some(material) => *material,
+        None => {
+          let material = match texture_pages.get(texture_page as usize) {
+            Some(Some(texture)) => push_textured_material(&mut builder, &mut images, &mut textures, &mut materials, texture_page, texture)?,
+            _ => {
+              materials.push(json!({
+                "name": format!("texture_page_{}", texture_page),
+                "pbrMetallicRoughness": { "metallicFactor": 0.0 },
+                "doubleSided": true
+              }));
+              materials.len() - 1
+            }
+          };
+          material_indices.insert(texture_page, material);
+          material
+        }
+      };
+
+      let position = builder.push_vec3(&primitive.positions);
+      let uv = builder.push_vec2(&primitive.uvs);
+      let indices = builder.push_indices(&primitive.indices);
+      json_primitives.push(json!({
+        "attributes": { "POSITION": position, "TEXCOORD_0": uv },
+        "indices": indices,
+        "material": material
+      }));
+    }
+
+    let mut node = json!({
+      "name": placed.name,
+      "translation": [placed.position.x as f32, placed.position.y as f32, placed.position.z as f32]
+    });
+    if !json_primitives.is_empty() {
+      meshes.push(json!({ "name": placed.name, "primitives": json_primitives }));
+      node["mesh"] = json!(meshes.len() - 1);
+    }
+    nodes.push(node);
+  }
+
+  pad_to_four_bytes(&mut builder.buffer, 0);
+
+  // glTF arrays may not be empty, so arrays with nothing in them are left out, along with the
+  // buffer and its BIN chunk when there is no binary data.
+  let mut gltf = json!({
+    "asset": { "version": "2.0", "generator": "rollcage-file-parser" }
+  });
+  if !nodes.is_empty() {
+    gltf["scene"] = json!(0);
+    gltf["scenes"] = json!([{ "nodes": (0..nodes.len()).collect::<Vec<usize>>() }]);
+  }
+  for (name, values) in [("nodes", nodes), ("meshes", meshes), ("materials", materials), ("bufferViews", builder.buffer_views), ("accessors", builder.accessors)] {
+    if !values.is_empty() {
+      gltf[name] = json!(values);
+    }
+  }
+  if !builder.buffer.is_empty() {
+    gltf["buffers"] = json!([{ "byteLength": builder.buffer.len() }]);
+  }
+  if !images.is_empty() {
+    gltf["images"] = json!(images);
+    gltf["textures"] = json!(textures);
+    gltf["samplers"] = json!([{ "magFilter": FILTER_NEAREST, "minFilter": FILTER_NEAREST }]);
+  }
+
+  let mut json_chunk = gltf.to_string().into_bytes();
+  pad_to_four_bytes(&mut json_chunk, b' ');
+
+  let bin_chunk_length = if builder.buffer.is_empty() { 0 } else { 8 + builder.buffer.len() };
+  let total_length = 12 + 8 + json_chunk.len() + bin_chunk_length;
+  let mut glb = Vec::with_capacity(total_length);
+  glb.extend_from_slice(GLB_MAGIC);
+  glb.extend_from_slice(&GLB_VERSION.to_le_bytes());
+  glb.extend_from_slice(&(total_length as u32).to_le_bytes());
+  glb.extend_from_slice(&(json_chunk.len() as u32).to_le_bytes());
+  glb.extend_from_slice(&GLB_CHUNK_JSON.to_le_bytes());
+  glb.extend_from_slice(&json_chunk);
+  if !builder.buffer.is_empty() {
+    glb.extend_from_slice(&(builder.buffer.len() as u32).to_le_bytes());
+    glb.extend_from_slice(&GLB_CHUNK_BIN.to_le_bytes());
+    glb.extend_from_slice(&builder.buffer);
+  }
+
+  Ok(glb)
+}
\ No newline at end of file
@@ -0,0 +1,117 @@
+use std::{borrow::Cow, convert::TryInto};
+
+use png::{BitDepth, ColorType, Decoder, DecodingError, Encoder, EncodingError, Transformations};
+
+use crate::file_formats::btp::{Colour, Palette, Texture, TexturePageInfo};
+
+// 8 bit paletted PNG of a texture. The palette is written as PLTE with its alpha as tRNS, and the
+// texture's indices are the image data, so paletted editors keep the indices as they are.
+pub fn texture_to_indexed_png(texture: &Texture) -> Result<Vec<u8>, EncodingError> {
+  let plte: Vec<u8> = texture.palette.data.iter().flat_map(|colour| [colour.red, colour.green, colour.blue]).collect();
+  let trns: Vec<u8> = texture.palette.data.iter().map(|colour| colour.alpha).collect();
+
+  let mut png = Vec::new();
+  let mut encoder = Encoder::new(&mut png, texture.info.width.into(), texture.info.height.into());
+  encoder.set_color(ColorType::Indexed);
+  encoder.set_depth(BitDepth::Eight);
+  encoder.set_palette(plte);
+  if trns.iter().any(|alpha| *alpha != u8::MAX) {
+    encoder.set_trns(trns);
+  }
+  encoder.write_header()?.write_image_data(&texture.image_data)?;
+  Ok(png)
+}
+
+// Reads a paletted PNG back into a texture for the texture page `info`, taking its palette from
+// PLTE and tRNS and its indices from the image data. Palettes shorter than 256 colours are padded
+// with opaque black. None when the PNG is not paletted.
+pub fn indexed_png_to_texture(info: TexturePageInfo, data: &[u8]) -> Result<Option<Texture>, DecodingError> {
+  let mut decoder = Decoder::new(data);
+  decoder.set_transformations(Transformations::IDENTITY);
+  let (output_info, mut reader) = decoder.read_info()?;
+  if output_info.color_type != ColorType::Indexed {
+    return Ok(None);
+  }
+
+  let mut packed = vec![0; reader.output_buffer_size()];
+  reader.next_frame(&mut packed)?;
+
+  let png_info = reader.info();
+  let plte = png_info.palette.clone().unwrap_or_default();
+  let trns = png_info.trns.clone().unwrap_or_default();
+  let mut palette = Palette { data: [Colour { red: 0, green: 0, blue: 0, alpha: u8::MAX }; 256] };
+  for (i, (colour, rgb)) in palette.data.iter_mut().zip(plte.chunks_exact(3)).enumerate() {
+    *colour = Colour { red: rgb[0], green: rgb[1], blue: rgb[2], alpha: trns.get(i).copied().unwrap_or(u8::MAX) };
+  }
+
+  // Rows of indices below 8 bits are packed, most significant bits first, and start on a new byte.
+  let bits = output_info.bit_depth as usize;
+  let width = output_info.width as usize;
+  let image_data: Vec<u8> = packed.chunks(output_info.line_size)
+    .take(output_info.height as usize)
+    .flat_map(|row| (0 .. width).map(move |x| {
+      let bit = x * bits;
+      (row[bit / 8] >> (8 - bits - bit % 8)) & ((1u16 << bits) - 1) as u8
+    }))
+    .collect();
+
+  let width = output_info.width.try_into().map_err(|_| DecodingError::Other(Cow::Borrowed("image is too wide for a texture page")))?;
+  let height = output_info.height.try_into().map_err(|_| DecodingError::Other(Cow::Borrowed("image is too tall for a texture page")))?;
+  Texture::new(TexturePageInfo { width, height, ..info }, palette, image_data)
+    .map(Some)
+    .map_err(|e| DecodingError::Other(Cow::Borrowed(e)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn info(palette: u32) -> TexturePageInfo {
+    TexturePageInfo { width: 0, height: 0, palette, texture_offset: 0x40 }
+  }
+
+  fn encode(width: u32, height: u32, color: ColorType, depth: BitDepth, palette: Option<Vec<u8>>, data: &[u8]) -> Vec<u8> {
+    let mut png = Vec::new();
+    let mut encoder = Encoder::new(&mut png, width, height);
+    encoder.set_color(color);
+    encoder.set_depth(depth);
+    if let Some(palette) = palette {
+      encoder.set_palette(palette);
+    }
+    encoder.write_header().unwrap().write_image_data(data).unwrap();
+    png
+  }
+
+  #[test]
+  fn exported_textures_import_with_the_same_indices_and_palette() {
+    let mut data = [Colour { red: 0, green: 0, blue: 0, alpha: 0 }; 256];
+    for (i, colour) in data.iter_mut().enumerate() {
+      *colour = Colour { red: i as u8, green: 255 - i as u8, blue: 7, alpha: (i * 3) as u8 };
+    }
+    let texture = Texture::new(TexturePageInfo { width: 5, height: 3, ..info(2) }, Palette { data }, (0 .. 15).map(|i| i * 17).collect()).unwrap();
+
+    let png = texture_to_indexed_png(&texture).unwrap();
+    let imported = indexed_png_to_texture(info(2), &png).unwrap().unwrap();
+    assert_eq!((imported.info.width, imported.info.height, imported.info.palette, imported.info.texture_offset), (5, 3, 2, 0x40));
+    assert_eq!(imported.image_data, texture.image_data);
+    assert_eq!(imported.palette.to_bytes(), texture.palette.to_bytes());
+  }
+
+  #[test]
+  fn packed_indices_and_short_palettes_are_expanded() {
+    let plte: Vec<u8> = (0 .. 16).flat_map(|i| [i * 16, i, 1]).collect();
+    let png = encode(3, 2, ColorType::Indexed, BitDepth::Four, Some(plte), &[0x12, 0x30, 0xf0, 0x40]);
+    let imported = indexed_png_to_texture(info(0), &png).unwrap().unwrap();
+    assert_eq!(imported.image_data, vec![1, 2, 3, 15, 0, 4]);
+    let colour = imported.palette.data[15];
+    assert_eq!((colour.red, colour.green, colour.blue, colour.alpha), (240, 15, 1, 255));
+    let colour = imported.palette.data[16];
+    assert_eq!((colour.red, colour.green, colour.blue, colour.alpha), (0, 0, 0, 255));
+  }
+
+  #[test]
+  fn pngs_without_a_palette_are_not_imported() {
+    let png = encode(1, 1, ColorType::RGB, BitDepth::Eight, None, &[1, 2, 3]);
+    assert!(indexed_png_to_texture(info(0), &png).unwrap().is_none());
+  }
+}
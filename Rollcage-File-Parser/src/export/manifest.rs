@@ -0,0 +1,59 @@
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::file_formats::{identify_format, idx::IdxRecord, Format};
+
+// What extraction wrote for one archive record.
+#[derive(Debug, Clone)]
+pub struct ManifestEntry {
+  pub id: usize,
+  pub record: IdxRecord,
+  pub stored_format: Format, // Before GT20 decompression
+  pub format: Option<Format>, // None when the record could not be decoded
+  pub sha256: Option<String>, // Of the decoded data
+  pub files: Vec<String>, // Relative to the output folder
+  pub error: Option<String>
+}
+
+impl ManifestEntry {
+  pub fn new(id: usize, record: IdxRecord, stored_format: Format) -> Self {
+    Self { id, record, stored_format, format: None, sha256: None, files: Vec::new(), error: None }
+  }
+
+  // Records the format and hash of the record's decoded data.
+  pub fn set_data(&mut self, data: &[u8]) {
+    self.format = Some(identify_format(data));
+    self.sha256 = Some(sha256_hex(data));
+  }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+  Sha256::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn format_name(format: Format) -> String {
+  match format {
+    Format::Unknown => String::from("unknown"),
+    format => format.to_string()
+  }
+}
+
+pub fn manifest_to_json(entries: &[ManifestEntry]) -> String {
+  let records: Vec<Value> = entries.iter().map(|entry| json!({
+    "id": entry.id,
+    "file_offset": entry.record.file_offset,
+    "compressed_file_length": entry.record.compressed_file_length,
+    "decompressed_file_length": entry.record.decompressed_file_length,
+    "unused": entry.record.unused,
+    "stored_format": format_name(entry.stored_format),
+    "format": entry.format.map(format_name),
+    "sha256": entry.sha256,
+    "files": entry.files,
+    "error": entry.error
+  })).collect();
+
+  serde_json::to_string_pretty(&json!({
+    "num_records": entries.len(),
+    "records": records
+  })).unwrap()
+}
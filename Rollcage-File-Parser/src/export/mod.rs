@@ -0,0 +1,71 @@
+use crate::file_formats::{btp::Texture, gfxm::{Coordinate, Gfxm}, modl::Modl};
+
+pub mod atlas;
+pub mod cobjects;
+pub mod dds;
+pub mod gltf;
+pub mod indexed_png;
+pub mod manifest;
+pub mod obj;
+pub mod palette;
+pub mod trace;
+pub mod verify;
+
+const ORIGIN: Coordinate = Coordinate { x: 0, y: 0, z: 0 };
+
+// Size of the texture pages of faces whose page is not in the btp.
+const TEXTURE_PAGE_SIZE: f32 = 256.0;
+
+// A face corner's texture coordinates, which are in texels of the face's texture page, scaled to
+// the size of the page, with (0, 0) at its top left as in glTF.
+pub(crate) fn texture_coordinates(texture_pages: &[Option<Texture>], texture_page: u16, [u, v]: [u8; 2]) -> [f32; 2] {
+  let (width, height) = match texture_pages.get(texture_page as usize) {
+    Some(Some(texture)) => (texture.info.width as f32, texture.info.height as f32),
+    _ => (TEXTURE_PAGE_SIZE, TEXTURE_PAGE_SIZE)
+  };
+  [u as f32 / width, v as f32 / height]
+}
+
+// A MODL positioned in the scene, named after where it was referenced from.
+pub(crate) struct PlacedModl<'a> {
+  pub name: String,
+  pub modl: &'a Modl,
+  pub position: Coordinate
+}
+
+// Adds `modl` followed by every sub-model in its tree. The header gives sub-models no position of
+// their own, so they are placed with the MODL that holds them.
+fn place_modl<'a>(placed: &mut Vec<PlacedModl<'a>>, name: String, modl: &'a Modl, position: Coordinate) {
+  placed.push(PlacedModl { name: name.clone(), modl, position });
+  for (sub_model_index, sub_model) in modl.sub_models.iter().enumerate() {
+    place_modl(placed, format!("{}_sub_{}", name, sub_model_index), sub_model, position);
+  }
+}
+
+// Every MODL referenced by a SEGM section, followed by any MODL no section references, which is
+// placed at the origin. Each is followed by its sub-models.
+pub(crate) fn placed_modls(gfxm: &Gfxm) -> Vec<PlacedModl<'_>> {
+  let mut placed = Vec::new();
+  let mut is_referenced = vec![false; gfxm.modls.len()];
+
+  for (section_index, section) in gfxm.segm_sections.iter().enumerate() {
+    for entry in section.entries.iter() {
+      let modl = match gfxm.modls.get(entry.modl_index as usize) {
+        Some(modl) => modl,
+        None => continue
+      };
+      is_referenced[entry.modl_index as usize] = true;
+      let name = format!("segm_{}_modl_{}", section_index, entry.modl_index);
+      let position = *gfxm.coordinates.get(entry.coordinate_index as usize).unwrap_or(&ORIGIN);
+      place_modl(&mut placed, name, modl, position);
+    }
+  }
+
+  for (modl_index, modl) in gfxm.modls.iter().enumerate() {
+    if !is_referenced[modl_index] {
+      place_modl(&mut placed, format!("modl_{}", modl_index), modl, ORIGIN);
+    }
+  }
+
+  placed
+}
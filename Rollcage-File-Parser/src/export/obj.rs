@@ -0,0 +1,153 @@
+use std::collections::BTreeSet;
+
+use crate::file_formats::{btp::Texture, gfxm::Gfxm};
+
+use super::{placed_modls, texture_coordinates};
+
+fn material_name(texture_page: u16) -> String {
+  format!("texture_page_{}", texture_page)
+}
+
+// Wavefront OBJ with one group per placed MODL, referencing materials from `mtl_filename`.
+// `texture_pages` give the sizes the texture coordinates are scaled to, as for `gfxm_to_glb`.
+pub fn gfxm_to_obj(gfxm: &Gfxm, mtl_filename: &str, texture_pages: &[Option<Texture>]) -> String {
+  let mut obj = format!("mtllib {}\n", mtl_filename);
+  let mut num_vertices: usize = 0;
+  let mut num_uvs: usize = 0;
+
+  for placed in placed_modls(gfxm) {
+    let modl = placed.modl;
+    obj.push_str(&format!("g {}\n", placed.name));
+
+    for vertex in modl.vertices.iter() {
+      obj.push_str(&format!("v {} {} {}\n",
+        vertex.x as i64 + placed.position.x as i64,
+        vertex.y as i64 + placed.position.y as i64,
+        vertex.z as i64 + placed.position.z as i64));
+    }
+
+    let mut current_material = None;
+    for face in modl.faces.iter() {
+      let corners = &face.vertex_indices[.. face.num_vertices()];
+      if corners.iter().any(|index| *index as usize >= modl.vertices.len()) {
+        continue;
+      }
+
+      if current_material != Some(face.texture_page) {
+        obj.push_str(&format!("usemtl {}\n", material_name(face.texture_page)));
+        current_material = Some(face.texture_page);
+      }
+
+      let mut face_line = String::from("f");
+      for (corner, vertex_index) in corners.iter().enumerate() {
+        // OBJ texture coordinates start at the bottom left.
+        let [u, v] = texture_coordinates(texture_pages, face.texture_page, face.uvs[corner]);
+        obj.push_str(&format!("vt {} {}\n", u, 1.0 - v));
+        num_uvs += 1;
+        face_line.push_str(&format!(" {}/{}", num_vertices + *vertex_index as usize + 1, num_uvs));
+      }
+      obj.push_str(&face_line);
+      obj.push('\n');
+    }
+
+    num_vertices += modl.vertices.len();
+  }
+
+  obj
+}
+
+// Texture pages used by the faces of every placed MODL.
+fn used_texture_pages(gfxm: &Gfxm) -> BTreeSet<u16> {
+  placed_modls(gfxm).iter()
+    .flat_map(|placed| placed.modl.faces.iter().map(|face| face.texture_page))
+    .collect()
+}
+
+// Name of the image `gfxm_to_mtl` refers to for a texture page.
+pub fn texture_filename(texture_page: u16) -> String {
+  format!("texture_page_{}.png", texture_page)
+}
+
+// The textures `gfxm_to_mtl` refers to, with their file names, which are to be saved in its
+// `texture_dir`.
+pub fn mtl_textures<'a>(gfxm: &Gfxm, texture_pages: &'a [Option<Texture>]) -> Vec<(String, &'a Texture)> {
+  used_texture_pages(gfxm).into_iter()
+    .filter_map(|texture_page| match texture_pages.get(texture_page as usize) {
+      Some(Some(texture)) => Some((texture_filename(texture_page), texture)),
+      _ => None
+    })
+    .collect()
+}
+
+// One material per texture page used by the faces. Pages found in `texture_pages` get their image
+// from `texture_dir`, relative to the mtl file, the others a distinct flat colour.
+pub fn gfxm_to_mtl(gfxm: &Gfxm, texture_pages: &[Option<Texture>], texture_dir: &str) -> String {
+  let mut mtl = String::new();
+  for texture_page in used_texture_pages(gfxm) {
+    mtl.push_str(&format!("newmtl {}\n", material_name(texture_page)));
+    match texture_pages.get(texture_page as usize) {
+      Some(Some(_)) => {
+        mtl.push_str("Kd 1.000 1.000 1.000\n");
+        mtl.push_str(&format!("map_Kd {}/{}\n\n", texture_dir, texture_filename(texture_page)));
+      }
+      _ => {
+        let hue = (texture_page as f32 * 0.618_034).fract();
+        mtl.push_str(&format!("Kd {:.3} {:.3} {:.3}\n\n", hue, 1.0 - hue, 0.5));
+      }
+    }
+  }
+  mtl
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::file_formats::btp::{Colour, Palette, TexturePageInfo};
+  use crate::file_formats::{gfxm::{Coordinate, GfxmHeader, SegmEntry, SegmSection}, modl::{parse_modl, tests::modl_bytes, MODL_HEADER_SIZE}};
+
+  // A gfxm placing one MODL, which has one sub-model, at `position`.
+  fn gfxm_with_sub_model(position: Coordinate) -> Gfxm {
+    let mut modl = modl_bytes(1, 3, 1, 0, [(MODL_HEADER_SIZE + 3 * 8 + 20) as u32, 0]);
+    modl.extend(modl_bytes(100, 3, 2, 0, [0, 0]));
+    let modl = parse_modl(&modl, 0, 0).unwrap();
+    Gfxm {
+      header: GfxmHeader { signature: *b"GFXM", unknown_1: 0, unknown_2: 0, num_coordinates: 1, num_segm_sections: 1, segm_table_offset: 0, modl_table_offset: 0 },
+      coordinates: vec![position],
+      segm_sections: vec![SegmSection { signature: *b"SEGM", entries: vec![SegmEntry { modl_index: 0, coordinate_index: 0 }] }],
+      modls: vec![modl]
+    }
+  }
+
+  #[test]
+  fn sub_models_are_exported_with_their_modl() {
+    let obj = gfxm_to_obj(&gfxm_with_sub_model(Coordinate { x: 10, y: 20, z: 30 }), "test.mtl", &[]);
+    let groups: Vec<_> = obj.lines().filter(|line| line.starts_with("g ")).collect();
+    assert_eq!(groups, ["g segm_0_modl_0", "g segm_0_modl_0_sub_0"]);
+    assert!(obj.contains("v 110 -80 30\n"));
+    // The sub-model's faces refer to its own vertices, which follow the three of its MODL.
+    assert_eq!(obj.lines().filter(|line| line.starts_with("f ")).nth(1), Some("f 4/4 5/5 6/6"));
+  }
+
+  #[test]
+  fn positions_at_the_edge_of_the_coordinate_range_do_not_overflow() {
+    let obj = gfxm_to_obj(&gfxm_with_sub_model(Coordinate { x: i32::MAX, y: i32::MIN, z: 0 }), "test.mtl", &[]);
+    assert!(obj.contains(&format!("v {} {} 0\n", i32::MAX as i64 + 1, i32::MIN as i64 - 1)));
+  }
+
+  #[test]
+  fn mtl_refers_to_the_textures_of_the_pages_it_finds() {
+    let gfxm = gfxm_with_sub_model(Coordinate { x: 0, y: 0, z: 0 });
+    let info = TexturePageInfo { width: 2, height: 1, palette: 0, texture_offset: 0 };
+    let palette = Palette { data: [Colour { red: 1, green: 2, blue: 3, alpha: 255 }; 256] };
+    let texture_pages = vec![Some(Texture::new(info, palette, vec![0, 0]).unwrap())];
+
+    let textures = mtl_textures(&gfxm, &texture_pages);
+    assert_eq!(textures.len(), 1);
+    assert_eq!(textures[0].0, "texture_page_0.png");
+
+    let mtl = gfxm_to_mtl(&gfxm, &texture_pages, "output3");
+    assert!(mtl.contains("newmtl texture_page_0\nKd 1.000 1.000 1.000\nmap_Kd output3/texture_page_0.png\n"));
+    assert!(mtl.contains("newmtl texture_page_1\nKd "));
+    assert_eq!(mtl.matches("map_Kd").count(), 1);
+  }
+}
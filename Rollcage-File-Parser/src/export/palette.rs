@@ -0,0 +1,135 @@
+use serde_json::json;
+
+use crate::file_formats::btp::{Palette, TexturePageInfo};
+
+// Texture page table entries that use each palette, by palette index. Pages without an image
+// are left out.
+pub fn palette_users(num_palettes: usize, texture_page_infos: &[TexturePageInfo]) -> Vec<Vec<usize>> {
+  let mut users = vec![Vec::new(); num_palettes];
+  for (texture_page, info) in texture_page_infos.iter().enumerate() {
+    if info.width == 0 || info.height == 0 {
+      continue;
+    }
+    if let Some(pages) = users.get_mut(info.palette as usize) {
+      pages.push(texture_page);
+    }
+  }
+  users
+}
+
+// Index of the first fully transparent colour, which formats without alpha can mark as such.
+fn transparent_index(palette: &Palette) -> Option<usize> {
+  palette.data.iter().position(|colour| colour.alpha == 0)
+}
+
+// Adobe colour table: 256 RGB triples followed by the colour count and the transparent colour
+// index, both big endian, 0xffff when there is none.
+pub fn palette_to_act(palette: &Palette) -> Vec<u8> {
+  let mut act: Vec<u8> = palette.data.iter().flat_map(|colour| [colour.red, colour.green, colour.blue]).collect();
+  act.extend_from_slice(&(palette.data.len() as u16).to_be_bytes());
+  act.extend_from_slice(&transparent_index(palette).map_or(0xffff, |index| index as u16).to_be_bytes());
+  act
+}
+
+// GIMP palette. The texture pages that use it are listed in a comment, and the alpha, which the
+// format cannot hold, is kept in each colour's name.
+pub fn palette_to_gpl(palette: &Palette, name: &str, texture_pages: &[usize]) -> String {
+  let pages: Vec<String> = texture_pages.iter().map(|page| page.to_string()).collect();
+  let mut gpl = format!("GIMP Palette\nName: {}\nColumns: 16\n# Used by texture pages: {}\n#\n", name, pages.join(", "));
+  for (index, colour) in palette.data.iter().enumerate() {
+    gpl += &format!("{:3} {:3} {:3}\tIndex {} alpha {}\n", colour.red, colour.green, colour.blue, index, colour.alpha);
+  }
+  gpl
+}
+
+// Paint Shop Pro palette. The format has no room for comments or alpha, see `palettes_to_json`.
+pub fn palette_to_jasc_pal(palette: &Palette) -> String {
+  let mut pal = format!("JASC-PAL\r\n0100\r\n{}\r\n", palette.data.len());
+  for colour in palette.data.iter() {
+    pal += &format!("{} {} {}\r\n", colour.red, colour.green, colour.blue);
+  }
+  pal
+}
+
+// Sidecar for the palette files: the texture pages that use each palette, the image numbers those
+// pages are extracted as, and the palette's alpha values.
+pub fn palettes_to_json(palettes: &[Palette], texture_page_infos: &[TexturePageInfo]) -> String {
+  let users = palette_users(palettes.len(), texture_page_infos);
+  // Extraction numbers the images of the pages that have one, in page order.
+  let mut image_numbers = vec![None; texture_page_infos.len()];
+  for (image, (page, _)) in texture_page_infos.iter().enumerate().filter(|(_, info)| info.width != 0 && info.height != 0).enumerate() {
+    image_numbers[page] = Some(image);
+  }
+
+  let entries: Vec<_> = palettes.iter().zip(users.iter()).enumerate().map(|(index, (palette, pages))| {
+    let images: Vec<usize> = pages.iter().filter_map(|page| image_numbers[*page]).collect();
+    let alpha: Vec<u8> = palette.data.iter().map(|colour| colour.alpha).collect();
+    json!({
+      "index": index,
+      "texture_pages": pages,
+      "images": images,
+      "transparent_index": transparent_index(palette),
+      "alpha": alpha
+    })
+  }).collect();
+  serde_json::to_string_pretty(&json!({ "palettes": entries })).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::Value;
+
+  use super::*;
+  use crate::file_formats::btp::Colour;
+
+  fn palette(transparent: Option<usize>) -> Palette {
+    let mut data = [Colour { red: 0, green: 0, blue: 0, alpha: 255 }; 256];
+    for (i, colour) in data.iter_mut().enumerate() {
+      *colour = Colour { red: i as u8, green: 1, blue: 255 - i as u8, alpha: if Some(i) == transparent { 0 } else { 255 } };
+    }
+    Palette { data }
+  }
+
+  fn page(width: u16, palette: u32) -> TexturePageInfo {
+    TexturePageInfo { width, height: width, palette, texture_offset: 0 }
+  }
+
+  #[test]
+  fn act_holds_rgb_triples_then_the_count_and_transparent_index() {
+    let act = palette_to_act(&palette(Some(0x23)));
+    assert_eq!(act.len(), 256 * 3 + 4);
+    assert_eq!(&act[.. 6], &[0, 1, 255, 1, 1, 254]);
+    assert_eq!(&act[256 * 3 ..], &[0x01, 0x00, 0x00, 0x23]);
+
+    let act = palette_to_act(&palette(None));
+    assert_eq!(&act[256 * 3 ..], &[0x01, 0x00, 0xff, 0xff]);
+  }
+
+  #[test]
+  fn gpl_and_jasc_list_every_colour() {
+    let gpl = palette_to_gpl(&palette(Some(2)), "palette_1", &[0, 3]);
+    let lines: Vec<&str> = gpl.lines().collect();
+    assert_eq!(&lines[.. 5], &["GIMP Palette", "Name: palette_1", "Columns: 16", "# Used by texture pages: 0, 3", "#"]);
+    assert_eq!(lines.len(), 5 + 256);
+    assert_eq!(lines[5 + 2], "  2   1 253\tIndex 2 alpha 0");
+
+    let pal = palette_to_jasc_pal(&palette(None));
+    assert!(pal.starts_with("JASC-PAL\r\n0100\r\n256\r\n0 1 255\r\n1 1 254\r\n"));
+    assert!(pal.ends_with("\r\n255 1 0\r\n"));
+    assert_eq!(pal.split("\r\n").count(), 3 + 256 + 1);
+  }
+
+  #[test]
+  fn json_numbers_images_skipping_empty_pages() {
+    let pages = [page(8, 1), page(0, 0), page(8, 0), page(8, 7)];
+    assert_eq!(palette_users(2, &pages), vec![vec![2], vec![0]]);
+
+    let json: Value = serde_json::from_str(&palettes_to_json(&[palette(Some(4)), palette(None)], &pages)).unwrap();
+    assert_eq!(json["palettes"][0]["texture_pages"], json!([2]));
+    assert_eq!(json["palettes"][0]["images"], json!([1]));
+    assert_eq!(json["palettes"][0]["transparent_index"], 4);
+    assert_eq!(json["palettes"][1]["images"], json!([0]));
+    assert_eq!(json["palettes"][1]["transparent_index"], Value::Null);
+    assert_eq!(json["palettes"][1]["alpha"].as_array().unwrap().len(), 256);
+  }
+}
@@ -0,0 +1,46 @@
+use serde_json::{json, Value};
+
+use crate::file_formats::gt::{TokenKind, Trace};
+
+// One line per token: the input and output offsets, then the token.
+pub fn trace_to_text(trace: &Trace) -> String {
+  let header = &trace.header;
+  let mut text = format!("uncompressed size {:#x}, overlap {:#x}, skip {:#x}\n", header.gt_uncompressed_size, header.gt_overlap, header.gt_skip);
+  text += &format!("{:>10} {:>10}  token\n", "input", "output");
+  for token in trace.tokens.iter() {
+    let description = match token.kind {
+      TokenKind::Literal { value } => format!("literal {:#04x}", value),
+      TokenKind::ShortMatch { offset, distance, length } => format!("short   distance {:#x} (offset {:#04x}) length {}", distance, offset, length),
+      TokenKind::LongMatch { distance, length, far } => format!("long    distance {:#x} length {}{}", distance, length, if far { " far" } else { "" }),
+      TokenKind::End => String::from("end")
+    };
+    text += &format!("{:#10x} {:#10x}  {}\n", token.input_offset, token.output_offset, description);
+  }
+  if let Some(error) = &trace.error {
+    text += &format!("error: {}\n", error);
+  }
+  text
+}
+
+pub fn trace_to_json(trace: &Trace) -> String {
+  let tokens: Vec<Value> = trace.tokens.iter().map(|token| {
+    let mut value = match token.kind {
+      TokenKind::Literal { value } => json!({ "type": "literal", "value": value }),
+      TokenKind::ShortMatch { offset, distance, length } => json!({ "type": "short_match", "offset": offset, "distance": distance, "length": length }),
+      TokenKind::LongMatch { distance, length, far } => json!({ "type": "long_match", "distance": distance, "length": length, "far": far }),
+      TokenKind::End => json!({ "type": "end" })
+    };
+    value["input_offset"] = json!(token.input_offset);
+    value["output_offset"] = json!(token.output_offset);
+    value
+  }).collect();
+
+  let header = &trace.header;
+  serde_json::to_string_pretty(&json!({
+    "uncompressed_size": header.gt_uncompressed_size,
+    "overlap": header.gt_overlap,
+    "skip": header.gt_skip,
+    "tokens": tokens,
+    "error": trace.error.as_ref().map(|error| json!({ "offset": error.offset(), "message": error.to_string() }))
+  })).unwrap()
+}
@@ -0,0 +1,53 @@
+use serde_json::{json, Value};
+
+use crate::archive::Issue;
+
+// Machine-readable verify report. Every issue has the record it concerns, a type, whether it is an
+// error and the message the text report prints, plus the values it was found from.
+pub fn issues_to_json(num_records: usize, issues: &[Issue]) -> String {
+  let entries: Vec<Value> = issues.iter().map(|issue| {
+    let mut value = match issue {
+      Issue::OutOfBounds { end, img_length, .. } => json!({ "type": "out_of_bounds", "end": end, "img_length": img_length }),
+      Issue::Overlap { other, length, .. } => json!({ "type": "overlap", "other": other, "length": length }),
+      Issue::Gap { previous, length, .. } => json!({ "type": "gap", "previous": previous, "length": length }),
+      Issue::SizeMismatch { header_size, decompressed_file_length, .. } => json!({ "type": "size_mismatch", "header_size": header_size, "decompressed_file_length": decompressed_file_length }),
+      Issue::LengthMismatch { compressed_file_length, decompressed_file_length, .. } => json!({ "type": "length_mismatch", "compressed_file_length": compressed_file_length, "decompressed_file_length": decompressed_file_length }),
+      Issue::Invalid { error, .. } => json!({ "type": "invalid", "format": error.format().to_string(), "offset": error.offset() }),
+      Issue::InPlaceFailure { error, .. } => json!({ "type": "in_place_failure", "offset": error.offset() })
+    };
+    value["record"] = json!(issue.entry());
+    value["error"] = json!(issue.is_error());
+    value["message"] = json!(issue.to_string());
+    value
+  }).collect();
+
+  let mut failed: Vec<usize> = issues.iter().filter(|issue| issue.is_error()).map(Issue::entry).collect();
+  failed.sort_unstable();
+  failed.dedup();
+
+  serde_json::to_string_pretty(&json!({
+    "num_records": num_records,
+    "num_failed": failed.len(),
+    "failed_records": failed,
+    "issues": entries
+  })).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn records_with_several_issues_fail_once() {
+    let issues = [
+      Issue::Overlap { entry: 3, other: 1, length: 2 },
+      Issue::LengthMismatch { entry: 1, compressed_file_length: 4, decompressed_file_length: 5 },
+      Issue::Gap { entry: 2, previous: Some(1), length: 1 },
+      Issue::OutOfBounds { entry: 3, end: 20, img_length: 16 }
+    ];
+    let report: Value = serde_json::from_str(&issues_to_json(4, &issues)).unwrap();
+    assert_eq!(report["num_failed"], 2);
+    assert_eq!(report["failed_records"], json!([1, 3]));
+    assert_eq!(report["issues"].as_array().unwrap().len(), 4);
+  }
+}
@@ -1,7 +1,12 @@
-use std::convert::TryInto;
+use std::{collections::{BTreeMap, BTreeSet, HashMap}, convert::TryInto};
+
+use color_quant::NeuQuant;
 
 use image::{ImageBuffer, Rgba, RgbaImage};
-use nom::{IResult, bytes::complete::{tag, take}, multi::many_m_n, number::complete::{le_u16, le_u32, le_u8}};
+use nom::{bytes::complete::{tag, take}, multi::count, number::complete::{le_i32, le_u16, le_u32, le_u8}};
+
+use crate::error::{seek, Error, ParseResult};
+use super::{count_sized, Format};
 
 extern crate image;
 
@@ -28,12 +33,12 @@ pub struct BtpHeader {
     pub palette_data_offset: u32,
 }
 
-pub fn parse_magic(input: &[u8]) -> IResult<&[u8], &[u8;BTP_MAGIC.len()]> {
+pub fn parse_magic(input: &[u8]) -> ParseResult<'_, &[u8;BTP_MAGIC.len()]> {
   let (input, signature) = tag(BTP_MAGIC)(input)?;
   Ok((input, signature.try_into().unwrap()))
 }
 
-pub fn parse_btp_header(input: &[u8]) -> IResult<&[u8], BtpHeader> {
+fn btp_header(input: &[u8]) -> ParseResult<'_, BtpHeader> {
   let (input, signature) = parse_magic(input)?;
   let (input, unknown_1) = le_u32(input)?; //take(16_usize)(input)?;
   let (input, unknown_2) = le_u32(input)?;
@@ -60,6 +65,13 @@ pub fn parse_btp_header(input: &[u8]) -> IResult<&[u8], BtpHeader> {
   )
 }
 
+pub fn parse_btp_header(input: &[u8]) -> Result<BtpHeader, Error> {
+  btp_header(input).map(|(_, header)| header).map_err(|e| Error::from_parse(input, Format::Btp, e))
+}
+
+pub const TEXTURE_PAGE_INFO_SIZE: usize = 12;
+pub const PALETTE_SIZE: usize = 256 * 4;
+
 #[derive(Debug, Clone, Copy)]
 pub struct TexturePageInfo {
   pub width: u16,
@@ -88,9 +100,9 @@ pub struct Texture {
   pub image_data: Vec<u8>
 }
 
-impl<'a> Texture {
+impl Texture {
 
-  pub fn new(info: TexturePageInfo, palette: Palette, image_data: Vec<u8>) -> Result<Self, &'a str> {
+  pub fn new(info: TexturePageInfo, palette: Palette, image_data: Vec<u8>) -> Result<Self, &'static str> {
     // validation
     match (info.width, info.height) {
       (0,0) => return Err("Texture width and height are zero."),
@@ -103,9 +115,8 @@ impl<'a> Texture {
       return Err("Texture palette data is not present.")
     }
 
-    let is_palette_and_data_valid: bool = image_data.iter().all(|lookup_index| palette.data.get(*lookup_index as usize).is_none());
-    if is_palette_and_data_valid {
-      Err("Texture data or palette is invalid. Some palette lookups were out of bounds.")
+    if image_data.len() != info.width as usize * info.height as usize {
+      Err("Texture data does not match the texture width and height.")
     }
     else {
       Ok(Self{info, palette, image_data})
@@ -118,14 +129,14 @@ impl<'a> Texture {
     
     ImageBuffer::from_fn(width, height, |x,y| {
       let index = (y*width) + x;
-      let i = self.image_data[index as usize];
-      let pixel_color = self.palette.data.get(i as usize).unwrap();
+      let i = self.image_data.get(index as usize).copied().unwrap_or(0);
+      let pixel_color = self.palette.data[i as usize];
       Rgba([pixel_color.red, pixel_color.green, pixel_color.blue, pixel_color.alpha])
     })
   }
 }
 
-fn parse_texture_page_table( input: &[u8] ) -> IResult<&[u8], TexturePageInfo> {
+fn parse_texture_page_table( input: &[u8] ) -> ParseResult<'_, TexturePageInfo> {
   let (input, o) = nom::sequence::tuple((le_u16, le_u16, le_u32, le_u32))(input)?;
   let (width, height, palette, texture_offset) = o;
   Ok(
@@ -133,67 +144,506 @@ fn parse_texture_page_table( input: &[u8] ) -> IResult<&[u8], TexturePageInfo> {
   )
 }
 
-pub fn parse_texture_page_infos(input: &[u8], texture_page_table_offset: usize, num_textures: usize ) -> IResult<&[u8], Vec<TexturePageInfo>> {
-  let input = input.get(texture_page_table_offset ..).ok_or(nom::Err::Incomplete(nom::Needed::Unknown))?;
-
-  let texture_page_infos= many_m_n(0, num_textures, parse_texture_page_table)(input)?.1;
-  Ok((input, texture_page_infos))
+pub fn parse_texture_page_infos(input: &[u8], texture_page_table_offset: usize, num_textures: usize ) -> Result<Vec<TexturePageInfo>, Error> {
+  let parse = |input| {
+    let table = seek(input, texture_page_table_offset)?;
+    count_sized(parse_texture_page_table, num_textures, TEXTURE_PAGE_INFO_SIZE)(table)
+  };
+  parse(input).map(|(_, texture_page_infos)| texture_page_infos).map_err(|e| Error::from_parse(input, Format::Btp, e))
 }
 
-pub fn parse_palettes(input: &[u8], palette_data_offset: usize, num_palettes: usize) -> IResult<&[u8], Vec<Palette>> {
-  let input = input.get(palette_data_offset as usize ..).ok_or(nom::Err::Incomplete(nom::Needed::Unknown))?;
-  let palettes= many_m_n(0, num_palettes as usize, parse_palette)(input)?.1;
-  Ok((input, palettes))
+pub fn parse_palettes(input: &[u8], palette_data_offset: usize, num_palettes: usize) -> Result<Vec<Palette>, Error> {
+  let parse = |input| {
+    let palette_data = seek(input, palette_data_offset)?;
+    count_sized(parse_palette, num_palettes, PALETTE_SIZE)(palette_data)
+  };
+  parse(input).map(|(_, palettes)| palettes).map_err(|e| Error::from_parse(input, Format::Btp, e))
 }
 
-pub fn parse_palette(input: &[u8]) -> IResult<&[u8], Palette> {
-  let (input, x) = many_m_n(0, 256, parse_colour)(input)?;
+pub fn parse_palette(input: &[u8]) -> ParseResult<'_, Palette> {
+  let (input, x) = count(parse_colour, 256)(input)?;
   let data: [Colour; 256] = x.try_into().unwrap();
   Ok((input, Palette{ data }))
 }
 
-pub fn parse_colour(input: &[u8]) -> IResult<&[u8], Colour> {
+pub fn parse_colour(input: &[u8]) -> ParseResult<'_, Colour> {
   let (input, (blue, green, red, alpha)) = nom::sequence::tuple((le_u8, le_u8, le_u8, le_u8))(input)?;
   let colour = Colour{red, green, blue, alpha};
   Ok((input, colour))
 }
 
-pub fn parse_texture_data<'a>(input: &'a[u8], header: &BtpHeader, texture_page_info: &TexturePageInfo) -> IResult<&'a [u8], Vec<u8>> {
+pub fn parse_texture_data(input: &[u8], header: &BtpHeader, texture_page_info: &TexturePageInfo) -> Result<Vec<u8>, Error> {
   let start_index: usize = header.texture_data_offset as usize +texture_page_info.texture_offset as usize;
   let num_bytes: usize = texture_page_info.height as usize * texture_page_info.width as usize;
-  let input = input.get( start_index ..).ok_or(nom::Err::Incomplete(nom::Needed::Unknown))?;
-  let (input, texture_data) = take(num_bytes)(input)?;
-  Ok( (input, texture_data.to_vec()) )
+  let parse = |input| {
+    let texture_data = seek(input, start_index)?;
+    take(num_bytes)(texture_data)
+  };
+  parse(input).map(|(_, texture_data): (&[u8], &[u8])| texture_data.to_vec()).map_err(|e| Error::from_parse(input, Format::Btp, e))
 }
 
-pub fn parse_textures<'a>(input: &'a [u8], header: &BtpHeader) -> IResult<&'a [u8], Vec<Texture>> {
-  let mut textures: Vec<Texture> = Vec::new();
+// One entry per texture page table entry, None where the page has no image.
+pub fn parse_texture_pages(input: &[u8], header: &BtpHeader) -> Result<Vec<Option<Texture>>, Error> {
   let texture_infos = parse_texture_page_infos(input,
-  header.texture_page_table_offset as usize, header.num_textures as usize).unwrap().1;
-  let palettes = parse_palettes(input, header.palette_data_offset as usize, header.num_palettes as usize).unwrap().1;
-  
-  if header.num_textures == 0 || header.num_palettes == 0 {
-      Ok((input, textures))
-  }
-  else {
-    for texture_index in 0..header.num_textures as usize {
-      let texture_info= *texture_infos.get(texture_index).unwrap();
-      let palette = *palettes.get(texture_info.palette as usize).unwrap();
-      if texture_info.width == 0 || texture_info.height == 0 {
-          continue;
+  header.texture_page_table_offset as usize, header.num_textures as usize)?;
+  let palettes = parse_palettes(input, header.palette_data_offset as usize, header.num_palettes as usize)?;
+
+  let mut texture_pages: Vec<Option<Texture>> = Vec::with_capacity(texture_infos.len());
+  for (texture_index, texture_info) in texture_infos.into_iter().enumerate() {
+    if texture_info.width == 0 || texture_info.height == 0 {
+      texture_pages.push(None);
+      continue;
+    }
+    let texture_info_offset = header.texture_page_table_offset as usize + texture_index * TEXTURE_PAGE_INFO_SIZE;
+    let palette = *palettes.get(texture_info.palette as usize)
+      .ok_or_else(|| Error::invalid_data(Format::Btp, texture_info_offset, "texture page refers to a missing palette"))?;
+    let texture_data = parse_texture_data(input, header, &texture_info)?;
+    let t = Texture::new(texture_info, palette, texture_data)
+      .map_err(|e| Error::invalid_data(Format::Btp, texture_info_offset, e))?;
+
+    texture_pages.push(Some(t));
+  }
+
+  Ok(texture_pages)
+}
+
+pub const COBJECT_SIZE: usize = 16;
+
+// One entry of the cobjects table: a position, in the same units as the gfxm coordinates, followed
+// by a word whose meaning is not known yet.
+#[derive(Debug, Clone, Copy)]
+pub struct CObject {
+  pub x: i32,
+  pub y: i32,
+  pub z: i32,
+  pub unknown_1: u32
+}
+
+fn parse_cobject(input: &[u8]) -> ParseResult<'_, CObject> {
+  let (input, (x, y, z, unknown_1)) = nom::sequence::tuple((le_i32, le_i32, le_i32, le_u32))(input)?;
+  Ok((input, CObject{x, y, z, unknown_1}))
+}
+
+// The cobjects table holds `num_cobjects` entries of `COBJECT_SIZE` bytes. Anything after them,
+// like padding, is not part of the table.
+pub fn parse_cobjects(input: &[u8], header: &BtpHeader) -> Result<Vec<CObject>, Error> {
+  if header.cobjects_data_offset == 0 || header.num_cobjects == 0 {
+    return Ok(Vec::new());
+  }
+  let parse = |input| {
+    let table = seek(input, header.cobjects_data_offset as usize)?;
+    count_sized(parse_cobject, header.num_cobjects as usize, COBJECT_SIZE)(table)
+  };
+  parse(input).map(|(_, cobjects)| cobjects).map_err(|e| Error::from_parse(input, Format::Btp, e))
+}
+
+pub fn parse_textures(input: &[u8], header: &BtpHeader) -> Result<Vec<Texture>, Error> {
+  let texture_pages = parse_texture_pages(input, header)?;
+  Ok(texture_pages.into_iter().flatten().collect())
+}
+
+pub const BTP_HEADER_SIZE: usize = 64;
+
+// NeuQuant sampling factor, 1 is the slowest and best. 10 is its usual default.
+const QUANTIZE_SAMPLE_FACTOR: i32 = 10;
+
+impl BtpHeader {
+  pub fn to_bytes(&self) -> [u8; BTP_HEADER_SIZE] {
+    let mut bytes = Vec::with_capacity(BTP_HEADER_SIZE);
+    bytes.extend_from_slice(&self.signature);
+    for value in [self.unknown_1, self.unknown_2, self.unknown_3, self.unknown_4, self.num_cobjects, self.unknown_5,
+      self.skybox_data_offset, self.unknown_6, self.texture_data_offset, self.cobjects_data_offset, self.unknown_7, self.unknown_8].iter() {
+      bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    bytes.extend_from_slice(&self.num_textures.to_le_bytes());
+    bytes.extend_from_slice(&self.num_palettes.to_le_bytes());
+    bytes.extend_from_slice(&self.texture_page_table_offset.to_le_bytes());
+    bytes.extend_from_slice(&self.palette_data_offset.to_le_bytes());
+    bytes.try_into().unwrap()
+  }
+}
+
+impl TexturePageInfo {
+  pub fn to_bytes(&self) -> [u8; TEXTURE_PAGE_INFO_SIZE] {
+    let mut bytes = [0; TEXTURE_PAGE_INFO_SIZE];
+    bytes[0..2].copy_from_slice(&self.width.to_le_bytes());
+    bytes[2..4].copy_from_slice(&self.height.to_le_bytes());
+    bytes[4..8].copy_from_slice(&self.palette.to_le_bytes());
+    bytes[8..12].copy_from_slice(&self.texture_offset.to_le_bytes());
+    bytes
+  }
+}
+
+impl Colour {
+  // Same BGRA order `parse_colour` reads.
+  pub fn to_bytes(&self) -> [u8; 4] {
+    [self.blue, self.green, self.red, self.alpha]
+  }
+}
+
+impl Palette {
+  pub fn to_bytes(&self) -> [u8; PALETTE_SIZE] {
+    let mut bytes = [0; PALETTE_SIZE];
+    for (colour, chunk) in self.data.iter().zip(bytes.chunks_exact_mut(4)) {
+      chunk.copy_from_slice(&colour.to_bytes());
+    }
+    bytes
+  }
+
+  // Index of the palette colour closest to `rgba`, an exact match if there is one. Fully
+  // transparent pixels map to a fully transparent colour and visible pixels to a visible one,
+  // whenever the palette has one.
+  pub fn closest_index(&self, rgba: [u8; 4]) -> u8 {
+    let transparent = rgba[3] == 0;
+    let candidates = || self.data.iter().enumerate().filter(move |(_, colour)| (colour.alpha == 0) == transparent);
+    if transparent {
+      if let Some((index, _)) = candidates().next() {
+        return index as u8;
+      }
+    }
+
+    let distance = |colour: &Colour| {
+      [colour.red, colour.green, colour.blue, colour.alpha].iter().zip(rgba.iter())
+        .map(|(a, b)| (*a as i32 - *b as i32).pow(2))
+        .sum::<i32>()
+    };
+    let (index, _) = candidates().min_by_key(|(_, colour)| distance(colour))
+      .or_else(|| self.data.iter().enumerate().min_by_key(|(_, colour)| distance(colour)))
+      .unwrap();
+    index as u8
+  }
+
+  // Builds a palette for `images`, which are all to be indexed with it, as the textures sharing a
+  // palette slot are. Fully transparent pixels share one transparent black entry at index 0. The
+  // other colours are kept as they are when there are few enough of them and quantized otherwise.
+  // Images without translucent pixels get a palette that is fully opaque apart from that entry.
+  pub fn quantize(images: &[&RgbaImage]) -> Palette {
+    let visible: Vec<[u8; 4]> = images.iter()
+      .flat_map(|image| image.pixels().map(|pixel| pixel.0))
+      .filter(|rgba| rgba[3] != 0)
+      .collect();
+    let has_transparency = images.iter().any(|image| image.pixels().any(|pixel| pixel.0[3] == 0));
+    let first_visible = if has_transparency { 1 } else { 0 };
+    let num_visible = 256 - first_visible;
+
+    let unique: BTreeSet<[u8; 4]> = visible.iter().copied().collect();
+    let colours: Vec<[u8; 4]> = if unique.len() <= num_visible {
+      unique.into_iter().collect()
+    } else {
+      let pixels: Vec<u8> = visible.iter().flatten().copied().collect();
+      let all_opaque = visible.iter().all(|rgba| rgba[3] == u8::MAX);
+      NeuQuant::new(QUANTIZE_SAMPLE_FACTOR, num_visible, &pixels).color_map_rgba()
+        .chunks_exact(4)
+        .map(|c| [c[0], c[1], c[2], if all_opaque { u8::MAX } else { c[3].max(1) }])
+        .collect()
+    };
+
+    let mut data = [Colour { red: 0, green: 0, blue: 0, alpha: 0 }; 256];
+    for (entry, [red, green, blue, alpha]) in data[first_visible ..].iter_mut().zip(colours) {
+      *entry = Colour { red, green, blue, alpha };
+    }
+    Palette { data }
+  }
+}
+
+impl Texture {
+  // Converts an edited image back to indexed data, mapping every pixel to the closest colour of
+  // `palette`. `info` keeps its palette index and texture offset, its size is taken from `image`.
+  pub fn from_rgba_image(info: TexturePageInfo, palette: Palette, image: &RgbaImage) -> Result<Self, &'static str> {
+    let width: u16 = image.width().try_into().map_err(|_| "Texture width is too large.")?;
+    let height: u16 = image.height().try_into().map_err(|_| "Texture height is too large.")?;
+
+    let mut indices: HashMap<[u8; 4], u8> = HashMap::new();
+    let image_data = image.pixels()
+      .map(|pixel| *indices.entry(pixel.0).or_insert_with(|| palette.closest_index(pixel.0)))
+      .collect();
+
+    Texture::new(TexturePageInfo { width, height, ..info }, palette, image_data)
+  }
+}
+
+// Offsets of the header's texture and palette counts.
+const NUM_TEXTURES_OFFSET: usize = 52;
+const NUM_PALETTES_OFFSET: usize = 54;
+
+// Serializes a btp holding only `texture_pages`, laid out as the header, the texture page table,
+// the palettes and the texture data. Each texture's palette is written at the palette index in
+// its `TexturePageInfo`; palette slots no texture refers to are left zeroed. Textures sharing a
+// palette slot must have the same palette. The fields of `header` the parser does not understand
+// are kept, but the skybox and cobjects are not written, so their offsets and count are cleared.
+pub fn write_btp(header: &BtpHeader, texture_pages: &[Option<Texture>]) -> Result<Vec<u8>, Error> {
+  let num_textures: u16 = texture_pages.len().try_into()
+    .map_err(|_| Error::invalid_data(Format::Btp, NUM_TEXTURES_OFFSET, "too many texture pages for a btp"))?;
+  let num_palettes = texture_pages.iter().flatten()
+    .map(|texture| texture.info.palette as usize + 1)
+    .fold(header.num_palettes as usize, usize::max);
+  let num_palettes: u16 = num_palettes.try_into()
+    .map_err(|_| Error::invalid_data(Format::Btp, NUM_PALETTES_OFFSET, "too many palettes for a btp"))?;
+
+  let mut palettes: Vec<Option<Palette>> = vec![None; num_palettes as usize];
+  for (texture_index, texture_page) in texture_pages.iter().enumerate() {
+    let texture = match texture_page {
+      Some(texture) => texture,
+      None => continue
+    };
+    let palette = palettes[texture.info.palette as usize].get_or_insert(texture.palette);
+    if palette.to_bytes() != texture.palette.to_bytes() {
+      let info_offset = BTP_HEADER_SIZE + texture_index * TEXTURE_PAGE_INFO_SIZE;
+      return Err(Error::invalid_data(Format::Btp, info_offset, "texture's palette differs from another texture's in the same palette slot"));
+    }
+  }
+
+  let texture_page_table_offset = BTP_HEADER_SIZE;
+  let palette_data_offset = texture_page_table_offset + texture_pages.len() * TEXTURE_PAGE_INFO_SIZE;
+  let texture_data_offset = palette_data_offset + num_palettes as usize * PALETTE_SIZE;
+
+  let mut texture_page_table = Vec::with_capacity(texture_pages.len() * TEXTURE_PAGE_INFO_SIZE);
+  let mut texture_data = Vec::new();
+  for texture_page in texture_pages {
+    let info = match texture_page {
+      Some(texture) => {
+        let info = TexturePageInfo { texture_offset: texture_data.len() as u32, ..texture.info };
+        texture_data.extend_from_slice(&texture.image_data);
+        info
+      }
+      None => TexturePageInfo { width: 0, height: 0, palette: 0, texture_offset: 0 }
+    };
+    texture_page_table.extend_from_slice(&info.to_bytes());
+  }
+
+  let header = BtpHeader {
+    num_cobjects: 0,
+    skybox_data_offset: 0,
+    texture_data_offset: texture_data_offset as u32,
+    cobjects_data_offset: 0,
+    num_textures,
+    num_palettes,
+    texture_page_table_offset: texture_page_table_offset as u32,
+    palette_data_offset: palette_data_offset as u32,
+    ..*header
+  };
+
+  let mut output = Vec::with_capacity(texture_data_offset + texture_data.len());
+  output.extend_from_slice(&header.to_bytes());
+  output.extend_from_slice(&texture_page_table);
+  for palette in palettes {
+    output.extend_from_slice(&palette.map_or([0; PALETTE_SIZE], |palette| palette.to_bytes()));
+  }
+  output.extend_from_slice(&texture_data);
+  Ok(output)
+}
+
+// Writes edited texture pages back into an existing btp, keeping everything else in it (the
+// skybox, cobjects and unknown fields) as it is. `texture_pages` must have one entry per texture
+// page of `input`, each the same size as the page it replaces; None leaves a page unchanged.
+// A texture's palette is written over the palette its page refers to, which other pages may share.
+// A slot only gets a different palette when every page using it is replaced with that palette, so
+// no page is left showing colours its indices were not drawn for.
+pub fn rebuild_btp(input: &[u8], texture_pages: &[Option<Texture>]) -> Result<Vec<u8>, Error> {
+  let header = parse_btp_header(input)?;
+  let original_pages = parse_texture_pages(input, &header)?;
+  let table_offset = header.texture_page_table_offset as usize;
+  if texture_pages.len() != original_pages.len() {
+    return Err(Error::invalid_data(Format::Btp, table_offset, "number of texture pages does not match the btp"));
+  }
+
+  let mut output = input.to_vec();
+  for (texture_index, (texture, original)) in texture_pages.iter().zip(original_pages.iter()).enumerate() {
+    let (texture, original) = match (texture, original) {
+      (None, _) => continue,
+      (Some(texture), Some(original)) => (texture, original),
+      (Some(_), None) => return Err(Error::invalid_data(Format::Btp, table_offset + texture_index * TEXTURE_PAGE_INFO_SIZE, "texture page has no image to replace"))
+    };
+    let info_offset = table_offset + texture_index * TEXTURE_PAGE_INFO_SIZE;
+    if (texture.info.width, texture.info.height) != (original.info.width, original.info.height) {
+      return Err(Error::invalid_data(Format::Btp, info_offset, "texture size does not match the texture page"));
+    }
+    if texture.info.palette >= header.num_palettes as u32 {
+      return Err(Error::invalid_data(Format::Btp, info_offset, "texture page refers to a missing palette"));
+    }
+
+    // Every range below was checked by `parse_texture_pages`.
+    let info = TexturePageInfo { texture_offset: original.info.texture_offset, ..texture.info };
+    output[info_offset .. info_offset + TEXTURE_PAGE_INFO_SIZE].copy_from_slice(&info.to_bytes());
+    let palette_offset = header.palette_data_offset as usize + info.palette as usize * PALETTE_SIZE;
+    output[palette_offset .. palette_offset + PALETTE_SIZE].copy_from_slice(&texture.palette.to_bytes());
+    let data_offset = header.texture_data_offset as usize + info.texture_offset as usize;
+    output[data_offset .. data_offset + texture.image_data.len()].copy_from_slice(&texture.image_data);
+  }
+
+  let mut slot_palettes: BTreeMap<u32, [u8; PALETTE_SIZE]> = BTreeMap::new();
+  for (texture_index, texture) in texture_pages.iter().enumerate() {
+    if let Some(texture) = texture {
+      let palette = texture.palette.to_bytes();
+      if *slot_palettes.entry(texture.info.palette).or_insert(palette) != palette {
+        let info_offset = table_offset + texture_index * TEXTURE_PAGE_INFO_SIZE;
+        return Err(Error::invalid_data(Format::Btp, info_offset, "textures sharing a palette slot have different palettes"));
       }
-      let texture_data = parse_texture_data(input, &header, &texture_info).unwrap().1;
-      let t = match Texture::new(texture_info, palette, texture_data) {
-        Ok(tex) => tex,
-        Err(e) => {
-          println!("Failed to create texture: {}", e);
-          continue;
+    }
+  }
+  for (slot, palette) in slot_palettes.iter() {
+    let palette_offset = header.palette_data_offset as usize + *slot as usize * PALETTE_SIZE;
+    if input[palette_offset .. palette_offset + PALETTE_SIZE] == palette[..] {
+      continue;
+    }
+    let kept_page = original_pages.iter().zip(texture_pages.iter())
+      .position(|(original, texture)| texture.is_none() && original.as_ref().is_some_and(|original| original.info.palette == *slot));
+    if let Some(texture_index) = kept_page {
+      let info_offset = table_offset + texture_index * TEXTURE_PAGE_INFO_SIZE;
+      return Err(Error::invalid_data(Format::Btp, info_offset, "texture page shares a palette slot that gets a new palette but is not replaced"));
+    }
+  }
+
+  Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn empty_header() -> BtpHeader {
+    let mut bytes = [0; BTP_HEADER_SIZE];
+    bytes[.. BTP_MAGIC.len()].copy_from_slice(BTP_MAGIC);
+    parse_btp_header(&bytes).unwrap()
+  }
+
+  fn palette(shade: u8) -> Palette {
+    let mut data = [Colour { red: 0, green: 0, blue: 0, alpha: 0 }; 256];
+    for (i, colour) in data.iter_mut().enumerate() {
+      *colour = Colour { red: i as u8, green: shade, blue: 255 - i as u8, alpha: 255 };
+    }
+    Palette { data }
+  }
+
+  fn texture(width: u16, height: u16, palette_index: u32, palette: Palette) -> Texture {
+    let info = TexturePageInfo { width, height, palette: palette_index, texture_offset: 0 };
+    let image_data = (0 .. width as usize * height as usize).map(|i| i as u8).collect();
+    Texture::new(info, palette, image_data).unwrap()
+  }
+
+  #[test]
+  fn write_btp_round_trips_through_the_parser() {
+    let pages = vec![
+      Some(texture(4, 2, 1, palette(7))),
+      None,
+      Some(texture(3, 3, 0, palette(9))),
+      Some(texture(2, 2, 1, palette(7)))
+    ];
+    let btp = write_btp(&empty_header(), &pages).unwrap();
+
+    let header = parse_btp_header(&btp).unwrap();
+    assert_eq!((header.num_textures, header.num_palettes), (4, 2));
+    let parsed = parse_texture_pages(&btp, &header).unwrap();
+    assert_eq!(parsed.len(), pages.len());
+    for (parsed, page) in parsed.iter().zip(pages.iter()) {
+      match (parsed, page) {
+        (Some(parsed), Some(page)) => {
+          assert_eq!((parsed.info.width, parsed.info.height, parsed.info.palette), (page.info.width, page.info.height, page.info.palette));
+          assert_eq!(parsed.image_data, page.image_data);
+          assert_eq!(parsed.palette.to_bytes(), page.palette.to_bytes());
         }
-      };
+        (None, None) => {}
+        _ => panic!("texture page was not kept")
+      }
+    }
+  }
+
+  #[test]
+  fn write_btp_rejects_different_palettes_in_one_slot() {
+    let pages = vec![Some(texture(2, 2, 0, palette(1))), Some(texture(2, 2, 0, palette(2)))];
+    let error = write_btp(&empty_header(), &pages).unwrap_err();
+    assert_eq!(error.offset(), BTP_HEADER_SIZE + TEXTURE_PAGE_INFO_SIZE);
+  }
+
+  #[test]
+  fn write_btp_rejects_counts_that_do_not_fit_the_header() {
+    let pages = vec![Some(texture(2, 2, u32::MAX - 1, palette(1)))];
+    let error = write_btp(&empty_header(), &pages).unwrap_err();
+    assert_eq!(error.offset(), NUM_PALETTES_OFFSET);
 
-      textures.push(t);
+    let pages = vec![None; u16::MAX as usize + 1];
+    let error = write_btp(&empty_header(), &pages).unwrap_err();
+    assert_eq!(error.offset(), NUM_TEXTURES_OFFSET);
+  }
+
+  #[test]
+  fn parse_cobjects_reads_whole_entries_and_ignores_padding() {
+    let header = BtpHeader { num_cobjects: 2, cobjects_data_offset: BTP_HEADER_SIZE as u32, ..empty_header() };
+    let mut btp = header.to_bytes().to_vec();
+    for value in [-5_i32, 10, 300_000].iter() {
+      btp.extend_from_slice(&value.to_le_bytes());
+    }
+    btp.extend_from_slice(&7_u32.to_le_bytes());
+    btp.extend_from_slice(&[1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0]);
+    btp.extend_from_slice(&[0; 4]);
+
+    let cobjects = parse_cobjects(&btp, &header).unwrap();
+    assert_eq!(cobjects.len(), 2);
+    assert_eq!((cobjects[0].x, cobjects[0].y, cobjects[0].z, cobjects[0].unknown_1), (-5, 10, 300_000, 7));
+    assert_eq!((cobjects[1].x, cobjects[1].y, cobjects[1].z, cobjects[1].unknown_1), (1, 2, 3, 4));
+
+    let truncated = &btp[.. BTP_HEADER_SIZE + COBJECT_SIZE + 8];
+    assert!(parse_cobjects(truncated, &header).is_err());
+  }
+
+  #[test]
+  fn quantize_keeps_the_colours_of_small_images_after_a_transparent_entry() {
+    let image = RgbaImage::from_fn(4, 3, |x, y| if x == 0 { Rgba([9, 9, 9, 0]) } else { Rgba([x as u8 * 60, y as u8 * 60, 5, 255]) });
+    let palette = Palette::quantize(&[&image]);
+    assert_eq!(palette.data[0].alpha, 0);
+    assert!(palette.data[1 ..= 9].iter().all(|colour| colour.alpha == 255));
+
+    let info = TexturePageInfo { width: 0, height: 0, palette: 2, texture_offset: 0 };
+    let texture = Texture::from_rgba_image(info, palette, &image).unwrap();
+    assert_eq!((texture.info.width, texture.info.height, texture.info.palette), (4, 3, 2));
+    let rgba = texture.to_rgba_image();
+    for ((x, y, pixel), index) in image.enumerate_pixels().zip(texture.image_data.iter()) {
+      if x == 0 {
+        assert_eq!(*index, 0);
+      } else {
+        assert_ne!(*index, 0);
+        assert_eq!(rgba.get_pixel(x, y), pixel);
+      }
+    }
+
+    let opaque = RgbaImage::from_pixel(2, 2, Rgba([1, 2, 3, 255]));
+    let palette = Palette::quantize(&[&opaque]);
+    assert_eq!((palette.data[0].red, palette.data[0].green, palette.data[0].blue, palette.data[0].alpha), (1, 2, 3, 255));
+  }
+
+  #[test]
+  fn quantize_does_not_give_index_0_to_visible_pixels_of_large_images() {
+    let image = RgbaImage::from_fn(32, 32, |x, y| if (x + y) % 7 == 0 { Rgba([0, 0, 0, 0]) } else { Rgba([x as u8 * 8, y as u8 * 8, (x * y) as u8, 255]) });
+    let palette = Palette::quantize(&[&image]);
+    assert_eq!(palette.data[0].alpha, 0);
+    assert!(palette.data[1 ..].iter().all(|colour| colour.alpha == 255));
+
+    let info = TexturePageInfo { width: 0, height: 0, palette: 0, texture_offset: 0 };
+    let texture = Texture::from_rgba_image(info, palette, &image).unwrap();
+    for (pixel, index) in image.pixels().zip(texture.image_data.iter()) {
+      assert_eq!(pixel.0[3] == 0, *index == 0);
     }
-    
-    Ok((input, textures))
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn rebuild_btp_only_replaces_shared_palettes_on_every_page_using_them() {
+    let pages = vec![Some(texture(2, 2, 0, palette(1))), Some(texture(2, 2, 1, palette(2))), Some(texture(2, 2, 1, palette(2)))];
+    let btp = write_btp(&empty_header(), &pages).unwrap();
+
+    let one_page = vec![None, Some(texture(2, 2, 1, palette(3))), None];
+    let error = rebuild_btp(&btp, &one_page).unwrap_err();
+    assert_eq!(error.offset(), BTP_HEADER_SIZE + 2 * TEXTURE_PAGE_INFO_SIZE);
+
+    let different_palettes = vec![None, Some(texture(2, 2, 1, palette(3))), Some(texture(2, 2, 1, palette(4)))];
+    let error = rebuild_btp(&btp, &different_palettes).unwrap_err();
+    assert_eq!(error.offset(), BTP_HEADER_SIZE + 2 * TEXTURE_PAGE_INFO_SIZE);
+
+    let every_page = vec![None, Some(texture(2, 2, 1, palette(3))), Some(texture(2, 2, 1, palette(3)))];
+    let rebuilt = rebuild_btp(&btp, &every_page).unwrap();
+    let palettes = parse_palettes(&rebuilt, BTP_HEADER_SIZE + 3 * TEXTURE_PAGE_INFO_SIZE, 2).unwrap();
+    assert_eq!(palettes[1].to_bytes(), palette(3).to_bytes());
+
+    let same_palette = vec![Some(texture(2, 2, 0, palette(1))), None, None];
+    assert!(rebuild_btp(&btp, &same_palette).is_ok());
+  }
+}
@@ -1,8 +1,14 @@
 use std::convert::TryInto;
 
-use nom::{IResult, bytes::complete::{tag}, number::complete::le_u32};
+use nom::{bytes::complete::{tag}, number::complete::{le_i32, le_u32}};
+
+use crate::error::{seek, Error, ParseResult};
+use super::{count_sized, modl::{parse_modl, Modl}, Format};
 
 const GFXM_MAGIC: &[u8; 4] = &[0x47, 0x46, 0x58, 0x4D]; // "GFXM"
+const SEGM_MAGIC: &[u8; 4] = &[0x53, 0x45, 0x47, 0x4D]; // "SEGM"
+
+pub const GFXM_HEADER_SIZE: usize = 28;
 
 #[derive(Debug, Clone, Copy)]
 #[allow(non_snake_case)]
@@ -16,12 +22,12 @@ pub struct GfxmHeader {
   pub modl_table_offset: u32
 }
 
-pub fn parse_magic(input: &[u8]) -> IResult<&[u8], &[u8;GFXM_MAGIC.len()]>{
+pub fn parse_magic(input: &[u8]) -> ParseResult<'_, &[u8;GFXM_MAGIC.len()]>{
   let (input, signature) = tag(GFXM_MAGIC)(input)?;
   Ok((input, signature.try_into().unwrap()))
 }
 
-pub fn parse_gfxm_header(input: &[u8]) -> IResult<&[u8], GfxmHeader> {
+fn gfxm_header(input: &[u8]) -> ParseResult<'_, GfxmHeader> {
   let (input, signature) = parse_magic(input)?;
   let (input, unknown_1) = le_u32(input)?;
   let (input, unknown_2) = le_u32(input)?;
@@ -36,4 +42,173 @@ pub fn parse_gfxm_header(input: &[u8]) -> IResult<&[u8], GfxmHeader> {
        num_coordinates, num_segm_sections, segm_table_offset, modl_table_offset
     })
   )
-}
\ No newline at end of file
+}
+
+// The coordinate pool directly follows the header.
+#[derive(Debug, Clone, Copy)]
+pub struct Coordinate {
+  pub x: i32,
+  pub y: i32,
+  pub z: i32
+}
+
+// Places a MODL from the modl table at a coordinate from the coordinate pool.
+#[derive(Debug, Clone, Copy)]
+pub struct SegmEntry {
+  pub modl_index: u32,
+  pub coordinate_index: u32
+}
+
+#[derive(Debug, Clone)]
+pub struct SegmSection {
+  pub signature: [u8;SEGM_MAGIC.len()],
+  pub entries: Vec<SegmEntry>
+}
+
+#[derive(Debug, Clone)]
+pub struct Gfxm {
+  pub header: GfxmHeader,
+  pub coordinates: Vec<Coordinate>,
+  pub segm_sections: Vec<SegmSection>,
+  pub modls: Vec<Modl>
+}
+
+impl Gfxm {
+  // The MODLs a section references, with the coordinate each one is placed at.
+  pub fn section_modls<'a>(&'a self, section: &'a SegmSection) -> impl Iterator<Item = (&'a Modl, Option<&'a Coordinate>)> + 'a {
+    section.entries.iter().filter_map(move |entry| {
+      let modl = self.modls.get(entry.modl_index as usize)?;
+      Some((modl, self.coordinates.get(entry.coordinate_index as usize)))
+    })
+  }
+}
+
+pub fn parse_gfxm_header(input: &[u8]) -> Result<GfxmHeader, Error> {
+  gfxm_header(input).map(|(_, header)| header).map_err(|e| Error::from_parse(input, Format::Gfxm, e))
+}
+
+fn parse_coordinate(input: &[u8]) -> ParseResult<'_, Coordinate> {
+  let (input, (x, y, z)) = nom::sequence::tuple((le_i32, le_i32, le_i32))(input)?;
+  Ok((input, Coordinate{x, y, z}))
+}
+
+pub fn parse_coordinates(input: &[u8], num_coordinates: usize) -> Result<Vec<Coordinate>, Error> {
+  let parse = |input| count_sized(parse_coordinate, num_coordinates, 12)(seek(input, GFXM_HEADER_SIZE)?);
+  parse(input).map(|(_, coordinates)| coordinates).map_err(|e| Error::from_parse(input, Format::Gfxm, e))
+}
+
+fn parse_segm_entry(input: &[u8]) -> ParseResult<'_, SegmEntry> {
+  let (input, (modl_index, coordinate_index)) = nom::sequence::tuple((le_u32, le_u32))(input)?;
+  Ok((input, SegmEntry{modl_index, coordinate_index}))
+}
+
+// A SEGM section is its signature, an entry count and the entries.
+fn segm_section(input: &[u8]) -> ParseResult<'_, SegmSection> {
+  let (input, signature) = tag(SEGM_MAGIC)(input)?;
+  let (input, num_entries) = le_u32(input)?;
+  let (input, entries) = count_sized(parse_segm_entry, num_entries as usize, 8)(input)?;
+  Ok((input, SegmSection{signature: signature.try_into().unwrap(), entries}))
+}
+
+// The segm table holds one offset per section, from the start of the file.
+fn segm_sections(input: &[u8], segm_table_offset: usize, num_segm_sections: usize) -> ParseResult<'_, Vec<SegmSection>> {
+  let offsets = count_sized(le_u32, num_segm_sections, 4)(seek(input, segm_table_offset)?)?.1;
+
+  let mut sections = Vec::with_capacity(offsets.len());
+  for offset in offsets {
+    sections.push(segm_section(seek(input, offset as usize)?)?.1);
+  }
+  Ok((input, sections))
+}
+
+pub fn parse_segm_sections(input: &[u8], segm_table_offset: usize, num_segm_sections: usize) -> Result<Vec<SegmSection>, Error> {
+  segm_sections(input, segm_table_offset, num_segm_sections).map(|(_, sections)| sections).map_err(|e| Error::from_parse(input, Format::Gfxm, e))
+}
+
+// The modl table is a count followed by one offset per MODL, from the start of the table.
+fn modl_table_offsets(input: &[u8], modl_table_offset: usize) -> ParseResult<'_, Vec<u32>> {
+  let (table, num_modls) = le_u32(seek(input, modl_table_offset)?)?;
+  count_sized(le_u32, num_modls as usize, 4)(table)
+}
+
+pub fn parse_modl_table(input: &[u8], modl_table_offset: usize) -> Result<Vec<Modl>, Error> {
+  let offsets = modl_table_offsets(input, modl_table_offset).map_err(|e| Error::from_parse(input, Format::Gfxm, e))?.1;
+
+  let mut modls = Vec::with_capacity(offsets.len());
+  for offset in offsets {
+    modls.push(parse_modl(input, modl_table_offset + offset as usize, modl_table_offset)?);
+  }
+  Ok(modls)
+}
+
+pub fn parse_gfxm(input: &[u8]) -> Result<Gfxm, Error> {
+  let header = parse_gfxm_header(input)?;
+  let coordinates = parse_coordinates(input, header.num_coordinates as usize)?;
+  let segm_sections = parse_segm_sections(input, header.segm_table_offset as usize, header.num_segm_sections as usize)?;
+  let modls = parse_modl_table(input, header.modl_table_offset as usize)?;
+
+  Ok(Gfxm{header, coordinates, segm_sections, modls})
+}
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::file_formats::modl::tests::{modl_bytes, FACE_SIZE};
+  use crate::file_formats::modl::MODL_HEADER_SIZE;
+
+  fn push_u32s(bytes: &mut Vec<u8>, values: &[u32]) {
+    for value in values {
+      bytes.extend_from_slice(&value.to_le_bytes());
+    }
+  }
+
+  // A gfxm with two coordinates, one SEGM section placing both of its MODLs and a face index
+  // for the one face of each MODL after them.
+  fn gfxm_bytes() -> Vec<u8> {
+    let segm_table_offset = GFXM_HEADER_SIZE + 2 * 12;
+    let modl_table_offset = segm_table_offset + 4 + 8 + 2 * 8;
+    let modl_length = MODL_HEADER_SIZE + 3 * 8 + FACE_SIZE;
+    let face_index_offset = 12 + 2 * modl_length;
+
+    let mut bytes = GFXM_MAGIC.to_vec();
+    push_u32s(&mut bytes, &[0, 0, 2, 1, segm_table_offset as u32, modl_table_offset as u32]);
+    for value in [1000_i32, -2000, 3000, i32::MAX, 0, i32::MIN].iter() {
+      bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    push_u32s(&mut bytes, &[segm_table_offset as u32 + 4]);
+    bytes.extend_from_slice(SEGM_MAGIC);
+    push_u32s(&mut bytes, &[2, 1, 0, 0, 1]);
+    push_u32s(&mut bytes, &[2, 12, 12 + modl_length as u32]);
+    bytes.extend(modl_bytes(0, 3, 1, face_index_offset as u32, [0, 0]));
+    bytes.extend(modl_bytes(10, 3, 1, face_index_offset as u32 + 2, [0, 0]));
+    bytes.extend_from_slice(&[7, 0, 9, 0]);
+    bytes
+  }
+
+  #[test]
+  fn parses_coordinates_sections_and_modls() {
+    let gfxm = parse_gfxm(&gfxm_bytes()).unwrap();
+
+    let coordinates: Vec<_> = gfxm.coordinates.iter().map(|c| (c.x, c.y, c.z)).collect();
+    assert_eq!(coordinates, [(1000, -2000, 3000), (i32::MAX, 0, i32::MIN)]);
+    assert_eq!(gfxm.segm_sections.len(), 1);
+    assert_eq!(gfxm.modls.len(), 2);
+    assert_eq!(gfxm.modls[1].vertices[0].x, 10);
+    assert_eq!((gfxm.modls[0].face_indices.as_slice(), gfxm.modls[1].face_indices.as_slice()), (&[7][..], &[9][..]));
+
+    let placed: Vec<_> = gfxm.section_modls(&gfxm.segm_sections[0])
+      .map(|(modl, coordinate)| (modl.vertices[0].x, coordinate.map(|c| c.x)))
+      .collect();
+    assert_eq!(placed, [(10, Some(1000)), (0, Some(i32::MAX))]);
+  }
+
+  #[test]
+  fn rejects_tables_past_the_end_of_the_data() {
+    let bytes = gfxm_bytes();
+    let modl_table_offset = GFXM_HEADER_SIZE + 2 * 12 + 4 + 8 + 2 * 8;
+    assert!(parse_gfxm(&bytes[.. modl_table_offset + 8]).is_err());
+
+    let mut bytes = bytes;
+    bytes[12 .. 16].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert_eq!(parse_gfxm(&bytes).unwrap_err(), Error::truncated(Format::Gfxm, GFXM_HEADER_SIZE));
+  }
+}
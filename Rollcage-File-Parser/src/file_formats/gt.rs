@@ -1,9 +1,13 @@
-use std::convert::TryInto;
+use std::{convert::TryInto, io::{self, Read}};
 
-use nom::{IResult, bytes::complete::tag, number::complete::le_u32};
+use nom::{bytes::complete::tag, number::complete::le_u32};
+
+use crate::error::{Error, ParseResult};
+use super::Format;
 
 const GT_MAGIC: &[u8; 4] = &[0x47, 0x54, 0x32, 0x30]; // "GT20"
 
+#[derive(Debug, Clone, Copy)]
 pub struct GTHeader {
   pub gt_signature: [u8;4],
   pub gt_uncompressed_size: u32,
@@ -11,12 +15,12 @@ pub struct GTHeader {
   pub gt_skip: u32 // Number of bytes to skip to GT data
 }
 
-pub fn parse_magic(input: &[u8]) -> IResult<&[u8], &[u8;4]> {
+pub fn parse_magic(input: &[u8]) -> ParseResult<'_, &[u8;4]> {
   let (input, signature) = tag(GT_MAGIC)(input)?;
   Ok((input, signature.try_into().unwrap()))
 }
 
-pub fn parse_header(input: &[u8]) -> IResult<&[u8], GTHeader> {
+fn gt_header(input: &[u8]) -> ParseResult<'_, GTHeader> {
   let (input, signature) = parse_magic(input)?;
   let (input, gt_uncompressed_size) = le_u32(input)?;
   let (input, gt_overlap) = le_u32(input)?;
@@ -31,152 +35,951 @@ pub fn parse_header(input: &[u8]) -> IResult<&[u8], GTHeader> {
   )
 }
 
-macro_rules! READINFOBIT {
-  ($input:ident, $info_bits:ident, $input_index:ident, $info_count:ident) => {
-    {
-      $info_bits = u32::from_le_bytes($input.get($input_index .. ($input_index+std::mem::size_of::<u32>())).ok_or(DecompressionError::CorruptFile)?.try_into().unwrap());
-      $info_count = 32;
-      $input_index += std::mem::size_of::<u32>();
+pub fn parse_header(input: &[u8]) -> Result<GTHeader, Error> {
+  gt_header(input).map(|(_, header)| header).map_err(|e| Error::from_parse(input, Format::GT20, e))
+}
+
+// Where a decompressor reads the compressed file from and writes its output to.
+trait Buffers {
+  // The compressed file, header included.
+  fn file(&self) -> &[u8];
+  fn output_len(&self) -> usize;
+  fn output_byte(&self, index: usize) -> u8;
+  // `input_index` is the offset in the file of the next byte still to be read.
+  fn push(&mut self, value: u8, input_index: usize) -> Result<(), Error>;
+}
+
+// The compressed file and the output in their own buffers.
+struct SeparateBuffers<'a> {
+  file: &'a [u8],
+  output: Vec<u8>
+}
+
+impl Buffers for SeparateBuffers<'_> {
+  fn file(&self) -> &[u8] {
+    self.file
+  }
+
+  fn output_len(&self) -> usize {
+    self.output.len()
+  }
+
+  fn output_byte(&self, index: usize) -> u8 {
+    self.output[index]
+  }
+
+  fn push(&mut self, value: u8, _input_index: usize) -> Result<(), Error> {
+    self.output.push(value);
+    Ok(())
+  }
+}
+
+// The compressed file at `file_start` in the buffer the output is written to from its start, so
+// every write is checked to land before the next byte still to be read.
+struct SharedBuffer<'a> {
+  buffer: &'a mut [u8],
+  file_start: usize,
+  output_len: usize
+}
+
+impl Buffers for SharedBuffer<'_> {
+  fn file(&self) -> &[u8] {
+    &self.buffer[self.file_start ..]
+  }
+
+  fn output_len(&self) -> usize {
+    self.output_len
+  }
+
+  fn output_byte(&self, index: usize) -> u8 {
+    self.buffer[index]
+  }
+
+  fn push(&mut self, value: u8, input_index: usize) -> Result<(), Error> {
+    if self.output_len >= self.file_start + input_index {
+      return Err(Error::invalid_data(Format::GT20, input_index, "overlap is too small to decompress in place"));
     }
-};
+    self.buffer[self.output_len] = value;
+    self.output_len += 1;
+    Ok(())
+  }
+}
+
+// Decodes the GT data of a compressed file. Input offsets in errors are relative to the start of
+// the file.
+struct Decompressor<B> {
+  buffers: B,
+  input_index: usize,
+  uncompressed_size: usize,
+  info_bits: u32,
+  info_count: u16,
+  // Every token decoded so far, when tracing.
+  trace: Option<Vec<Token>>
 }
 
-macro_rules! NEXTINFOBIT {
-  ($input:ident, $info_bits:ident, $input_index:ident, $info_count:ident) => {
-    {
-      $info_bits >>= 1;
-      $info_count -= 1;
-      if $info_count == 0 {
-        READINFOBIT!($input, $info_bits, $input_index, $info_count);
+impl<B: Buffers> Decompressor<B> {
+  fn new(buffers: B, uncompressed_size: usize) -> Result<Self, Error> {
+    let file = buffers.file();
+    let header = parse_header(file)?;
+    // The GT data starts `gt_skip` bytes after the header.
+    let data_start = (std::mem::size_of::<GTHeader>() + header.gt_skip as usize).min(file.len());
+    Ok(Self { buffers, input_index: data_start, uncompressed_size, info_bits: 0, info_count: 0, trace: None })
+  }
+
+  fn read_bytes<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+    let start = self.input_index;
+    let bytes = self.buffers.file().get(start .. start + N).ok_or_else(|| Error::truncated(Format::GT20, start))?;
+    let bytes = bytes.try_into().unwrap();
+    self.input_index += N;
+    Ok(bytes)
+  }
+
+  fn read_info_bits(&mut self) -> Result<(), Error> {
+    self.info_bits = u32::from_le_bytes(self.read_bytes()?);
+    self.info_count = 32;
+    Ok(())
+  }
+
+  fn next_info_bit(&mut self) -> Result<(), Error> {
+    self.info_bits >>= 1;
+    self.info_count -= 1;
+    if self.info_count == 0 {
+      self.read_info_bits()?;
+    }
+    Ok(())
+  }
+
+  fn push(&mut self, value: u8) -> Result<(), Error> {
+    if self.buffers.output_len() >= self.uncompressed_size {
+      return Err(Error::invalid_data(Format::GT20, self.input_index, "decompressed data is larger than the expected size"));
+    }
+    self.buffers.push(value, self.input_index)
+  }
+
+  fn copy(&mut self, distance: usize, length: usize) -> Result<(), Error> {
+    for _ in 0 .. length {
+      let copy_index = self.buffers.output_len().checked_sub(distance)
+        .ok_or_else(|| Error::invalid_data(Format::GT20, self.input_index, "back reference before the start of the data"))?;
+      self.push(self.buffers.output_byte(copy_index))?;
+    }
+    Ok(())
+  }
+
+  fn record(&mut self, input_offset: usize, output_offset: usize, kind: TokenKind) {
+    if let Some(trace) = &mut self.trace {
+      trace.push(Token { input_offset, output_offset, kind });
+    }
+  }
+
+  fn run(&mut self) -> Result<(), Error> {
+    self.read_info_bits()?;
+
+    loop {
+      let (input_offset, output_offset) = (self.input_index, self.buffers.output_len());
+      if self.info_bits & 1 == 0 {
+        let [value] = self.read_bytes()?;
+        self.push(value)?;
+        self.record(input_offset, output_offset, TokenKind::Literal { value });
       }
+      else {
+        self.next_info_bit()?;
+
+        if self.info_bits & 1 != 0 {
+          let word = u16::from_le_bytes(self.read_bytes()?);
+          let mut distance = NEAR_MATCH_MAX_DISTANCE - (word >> 3) as usize;
+          let mut far = false;
+          let mut length = (word & 7) as usize;
+          if length != 0 {
+            length += 2;
+          }
+          else {
+            let [extra] = self.read_bytes()?;
+            if extra & 128 != 0 {
+              distance += NEAR_MATCH_MAX_DISTANCE;
+              far = true;
+            }
+            length = match extra & 127 {
+              1 => {
+                self.record(input_offset, output_offset, TokenKind::End);
+                break;
+              }
+              0 => u16::from_le_bytes(self.read_bytes()?) as usize,
+              count => count as usize + 2
+            };
+          }
+          self.record(input_offset, output_offset, TokenKind::LongMatch { distance, length, far });
+          self.copy(distance, length)?;
+        }
+        else {
+          let [offset] = self.read_bytes()?;
+          let distance = SHORT_MATCH_MAX_DISTANCE - offset as usize;
+          self.copy(distance, 2)?;
+          let mut length = 2;
+
+          self.next_info_bit()?;
+          if self.info_bits & 1 != 0 {
+            self.copy(distance, 2)?;
+            length += 2;
+          }
+
+          self.next_info_bit()?;
+          if self.info_bits & 1 != 0 {
+            self.copy(distance, 1)?;
+            length += 1;
+          }
+          self.record(input_offset, output_offset, TokenKind::ShortMatch { offset, distance, length });
+        }
+      }
+
+      self.next_info_bit()?;
     }
-};
+
+    Ok(())
+  }
 }
 
-macro_rules! COPY_ARRAY_VALUE {
-  ($dest_array:ident, $dest_index:ident, $src_array:ident, $src_index:ident) => {
-    {
-      *$dest_array.get_mut($dest_index).ok_or(DecompressionError::CorruptFile)? = *$src_array.get($src_index).ok_or(DecompressionError::CorruptFile)?;
-    }
-};
+// One step of GT20 decompression. `input_offset` is where the token's data starts in the file,
+// after the info bits that select it, and `output_offset` where its output starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token {
+  pub input_offset: usize,
+  pub output_offset: usize,
+  pub kind: TokenKind
 }
 
-#[derive(Debug)]
-pub enum DecompressionError {
-    IncorrectFileSignature,
-    CorruptFile
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+  Literal { value: u8 },
+  // 2 to 5 bytes copied from `distance` back, stored as `offset`, the distance relative to -256.
+  ShortMatch { offset: u8, distance: usize, length: usize },
+  // Far matches reach the 0x2000 bytes before the near range.
+  LongMatch { distance: usize, length: usize, far: bool },
+  End
 }
 
-pub fn decompress(input: &[u8], uncompressed_size: u32) -> Result<Vec<u8>, DecompressionError> {
-  match parse_magic(input) {
-    Ok(_) => {},
-    Err(_) => {return Err(DecompressionError::IncorrectFileSignature)},
+// Every token decoded from a GT20 file, up to the end marker or to the error that stopped it.
+#[derive(Debug, Clone)]
+pub struct Trace {
+  pub header: GTHeader,
+  pub tokens: Vec<Token>,
+  pub error: Option<Error>
 }
 
-  let mut output: Vec<u8> = vec![0;uncompressed_size as usize];
-  let mut info_bits: u32;
-  let mut info_count: u16;
+// The most a GT20 file of `compressed_length` bytes can decompress to. The longest match takes
+// five bytes of GT data and copies `LONG_MATCH_MAX_LENGTH` bytes.
+fn max_uncompressed_size(compressed_length: usize) -> usize {
+  (compressed_length / 5 + 1) * LONG_MATCH_MAX_LENGTH
+}
 
-  let mut copy_index: usize;
-  let mut copy_count: u16;
+// Decompresses `input` to separate output, allocated as it is written.
+fn decompressor(input: &[u8], uncompressed_size: u32) -> Result<Decompressor<SeparateBuffers<'_>>, Error> {
+  let uncompressed_size = uncompressed_size as usize;
+  if uncompressed_size > max_uncompressed_size(input.len()) {
+    return Err(Error::invalid_data(Format::GT20, 4, "uncompressed size is larger than the compressed data can hold"));
+  }
+  let buffers = SeparateBuffers { file: input, output: Vec::with_capacity(uncompressed_size) };
+  Decompressor::new(buffers, uncompressed_size)
+}
 
-  let mut input_index: usize = 0;
-  let mut output_index: usize = 0;
+// Decompresses `input` like `decompress`, keeping every token instead of the output.
+pub fn trace(input: &[u8], uncompressed_size: u32) -> Result<Trace, Error> {
+  let header = parse_header(input)?;
+  let mut decompressor = decompressor(input, uncompressed_size)?;
+  decompressor.trace = Some(Vec::new());
+  let error = decompressor.run().err();
+  Ok(Trace { header, tokens: decompressor.trace.unwrap_or_default(), error })
+}
+
+pub fn decompress(input: &[u8], uncompressed_size: u32) -> Result<Vec<u8>, Error> {
+  parse_magic(input).map_err(|e| Error::from_parse(input, Format::GT20, e))?;
 
-  // Advance Past Header
-  input_index += std::mem::size_of::<GTHeader>();
+  let mut decompressor = decompressor(input, uncompressed_size)?;
+  decompressor.run()?;
+  // Output the data stops short of is left zeroed.
+  let mut output = decompressor.buffers.output;
+  output.resize(uncompressed_size as usize, 0);
+  Ok(output)
+}
 
-  READINFOBIT!(input, info_bits, input_index, info_count);
+// Size of the buffer the game loader decompresses a GT20 file in: the uncompressed size plus the
+// overlap the output needs to stay behind the compressed data as it is read.
+pub fn in_place_buffer_size(header: &GTHeader) -> usize {
+  header.gt_uncompressed_size as usize + header.gt_overlap as usize
+}
 
-  loop {
-    let lsb = info_bits & 1;
-    if lsb == 0{
-      COPY_ARRAY_VALUE!(output, output_index, input, input_index);
-      input_index += 1;
-      output_index +=1;
-    } 
-    else {
-      NEXTINFOBIT!(input, info_bits, input_index, info_count);
+// Decompresses the way the game loader does, in a single buffer. The compressed file, header
+// included, is in the last `compressed_length` bytes of `buffer`, which should be
+// `in_place_buffer_size` long, and is decompressed to the start of it. Returns the uncompressed
+// size. Fails if the output would overwrite compressed data that has not been read yet.
+pub fn decompress_in_place(buffer: &mut [u8], compressed_length: usize) -> Result<usize, Error> {
+  let file_start = buffer.len().checked_sub(compressed_length)
+    .ok_or_else(|| Error::invalid_data(Format::GT20, 0, "compressed data is larger than the buffer"))?;
+  let header = parse_header(&buffer[file_start ..])?;
+  let uncompressed_size = header.gt_uncompressed_size as usize;
+
+  let buffers = SharedBuffer { buffer, file_start, output_len: 0 };
+  let mut decompressor = Decompressor::new(buffers, uncompressed_size)?;
+  decompressor.run()?;
+  Ok(uncompressed_size)
+}
+
+// Decompresses `input` in place in a buffer of `in_place_buffer_size`, as the game loader would,
+// which checks that the header's overlap is large enough. The header's uncompressed size must be
+// `uncompressed_size`, the size the idx record gives.
+pub fn decompress_with_overlap(input: &[u8], uncompressed_size: u32) -> Result<Vec<u8>, Error> {
+  let header = parse_header(input)?;
+  if header.gt_uncompressed_size != uncompressed_size {
+    return Err(Error::invalid_data(Format::GT20, 4, "uncompressed size differs from the expected size"));
+  }
+  if header.gt_uncompressed_size as usize > max_uncompressed_size(input.len()) {
+    return Err(Error::invalid_data(Format::GT20, 4, "uncompressed size is larger than the compressed data can hold"));
+  }
+  if input.len() > in_place_buffer_size(&header) {
+    return Err(Error::invalid_data(Format::GT20, 8, "overlap is too small to load the compressed data in place"));
+  }
+  // An overlap larger than the compressed file keeps the output clear of it either way.
+  let overlap = (header.gt_overlap as usize).min(input.len());
+  let buffer_size = header.gt_uncompressed_size as usize + overlap;
+  let mut buffer = vec![0; buffer_size];
+  buffer[buffer_size - input.len() ..].copy_from_slice(input);
+  let uncompressed_size = decompress_in_place(&mut buffer, input.len())?;
+  buffer.truncate(uncompressed_size);
+  Ok(buffer)
+}
 
-      let tmp = info_bits & 1;
-      if tmp != 0 {
-        copy_count = u16::from_le_bytes(input.get(input_index .. (input_index+std::mem::size_of::<u16>())).ok_or(DecompressionError::CorruptFile)?.try_into().unwrap());
-        input_index += std::mem::size_of::<u16>();
+const SHORT_MATCH_MAX_DISTANCE: usize = 0x100;
+const SHORT_MATCH_MIN_LENGTH: usize = 2;
+const SHORT_MATCH_MAX_LENGTH: usize = 5;
+const LONG_MATCH_MIN_LENGTH: usize = 3;
+const LONG_MATCH_PACKED_MAX_LENGTH: usize = 9; // length stored in the low 3 bits of the offset
+const LONG_MATCH_BYTE_MAX_LENGTH: usize = 129; // length stored in an extra byte
+const LONG_MATCH_MAX_LENGTH: usize = 0xffff; // length stored in an extra u16
+const NEAR_MATCH_MAX_DISTANCE: usize = 0x2000;
+const FAR_MATCH_MAX_DISTANCE: usize = 0x4000;
+const FAR_MATCH_MIN_LENGTH: usize = 4; // far matches always need the extra length byte
+
+const HASH_BITS: u32 = 15;
+const MAX_CHAIN_LENGTH: usize = 128;
+
+// Writes the info bit stream in the same order `decompress` consumes it: a new 32 bit info
+// word is reserved at the current output position as soon as the previous one is used up.
+struct InfoBitWriter {
+  output: Vec<u8>,
+  info_index: usize,
+  info_bits: u32,
+  info_count: u16
+}
+
+impl InfoBitWriter {
+  fn new(capacity: usize) -> Self {
+    let mut output = Vec::with_capacity(capacity);
+    output.extend_from_slice(&[0; std::mem::size_of::<GTHeader>()]);
+    let info_index = output.len();
+    output.extend_from_slice(&[0; std::mem::size_of::<u32>()]);
+    Self { output, info_index, info_bits: 0, info_count: 0 }
+  }
+
+  fn set_bit(&mut self, bit: bool) {
+    if bit {
+      self.info_bits |= 1 << self.info_count;
+    }
+  }
+
+  fn next_bit(&mut self) {
+    self.info_count += 1;
+    if self.info_count == 32 {
+      self.flush_info_bits();
+      self.info_index = self.output.len();
+      self.output.extend_from_slice(&[0; std::mem::size_of::<u32>()]);
+      self.info_bits = 0;
+      self.info_count = 0;
+    }
+  }
+
+  fn flush_info_bits(&mut self) {
+    self.output[self.info_index .. self.info_index + std::mem::size_of::<u32>()].copy_from_slice(&self.info_bits.to_le_bytes());
+  }
+
+  fn literal(&mut self, value: u8) {
+    self.set_bit(false);
+    self.output.push(value);
+    self.next_bit();
+  }
 
-        let offset = (copy_count >> 3) as u32 | 0xffffe000;
-        copy_index = (output_index as u32).wrapping_add(offset) as usize;
+  fn copy(&mut self, distance: usize, length: usize) {
+    self.set_bit(true);
+    self.next_bit();
 
-      copy_count &= 7;
-      if copy_count != 0 {
-          copy_count += 2;
+    if distance <= SHORT_MATCH_MAX_DISTANCE && length <= SHORT_MATCH_MAX_LENGTH {
+      self.set_bit(false);
+      self.output.push((SHORT_MATCH_MAX_DISTANCE - distance) as u8);
+      self.next_bit();
+      self.set_bit(length >= 4);
+      self.next_bit();
+      self.set_bit(length == 3 || length == 5);
+      self.next_bit();
+      return;
+    }
+
+    self.set_bit(true);
+    let is_far = distance > NEAR_MATCH_MAX_DISTANCE;
+    let offset = if is_far { FAR_MATCH_MAX_DISTANCE - distance } else { NEAR_MATCH_MAX_DISTANCE - distance } as u16;
+
+    if !is_far && length <= LONG_MATCH_PACKED_MAX_LENGTH {
+      self.output.extend_from_slice(&((offset << 3) | (length - 2) as u16).to_le_bytes());
+    }
+    else {
+      self.output.extend_from_slice(&(offset << 3).to_le_bytes());
+      let far_flag = if is_far { 128 } else { 0 };
+      if length <= LONG_MATCH_BYTE_MAX_LENGTH {
+        self.output.push(far_flag | (length - 2) as u8);
       }
       else {
-        copy_count = *input.get(input_index).ok_or(DecompressionError::CorruptFile)? as u16;
-        input_index += 1;
+        self.output.push(far_flag);
+        self.output.extend_from_slice(&(length as u16).to_le_bytes());
+      }
+    }
+    self.next_bit();
+  }
 
-        if (copy_count & 128) != 0 {
-          copy_index = copy_index.wrapping_sub(0x2000);
-        }
+  fn end(&mut self) {
+    self.set_bit(true);
+    self.next_bit();
+    self.set_bit(true);
+    self.output.extend_from_slice(&[0, 0, 1]);
+    self.flush_info_bits();
+  }
+}
 
-        copy_count &= 127;
+// Hash chains over 3 byte sequences plus the last position of every byte pair, which is all
+// that is needed for the 2 byte short matches.
+struct MatchFinder<'a> {
+  input: &'a [u8],
+  head: Vec<usize>,
+  previous: Vec<usize>,
+  last_pair: Vec<usize>
+}
 
-        if copy_count == 1 {
+impl<'a> MatchFinder<'a> {
+  fn new(input: &'a [u8]) -> Self {
+    Self {
+      input,
+      head: vec![usize::MAX; 1 << HASH_BITS],
+      previous: vec![usize::MAX; input.len()],
+      last_pair: vec![usize::MAX; 1 << 16]
+    }
+  }
+
+  fn hash(&self, position: usize) -> usize {
+    let value = u32::from_le_bytes([self.input[position], self.input[position + 1], self.input[position + 2], 0]);
+    (value.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+  }
+
+  fn pair(&self, position: usize) -> usize {
+    u16::from_le_bytes([self.input[position], self.input[position + 1]]) as usize
+  }
+
+  fn insert(&mut self, position: usize) {
+    if position + 3 <= self.input.len() {
+      let hash = self.hash(position);
+      self.previous[position] = self.head[hash];
+      self.head[hash] = position;
+    }
+    if position + 2 <= self.input.len() {
+      let pair = self.pair(position);
+      self.last_pair[pair] = position;
+    }
+  }
+
+  fn match_length(&self, candidate: usize, position: usize) -> usize {
+    let max_length = (self.input.len() - position).min(LONG_MATCH_MAX_LENGTH);
+    let mut length = 0;
+    while length < max_length && self.input[candidate + length] == self.input[position + length] {
+      length += 1;
+    }
+    length
+  }
+
+  // Returns the longest encodable (distance, length) match at `position`, or `None`.
+  fn find(&self, position: usize) -> Option<(usize, usize)> {
+    let mut best: Option<(usize, usize)> = None;
+
+    if position + LONG_MATCH_MIN_LENGTH <= self.input.len() {
+      let mut candidate = self.head[self.hash(position)];
+      let mut chain_length = 0;
+      while candidate != usize::MAX && chain_length < MAX_CHAIN_LENGTH {
+        let distance = position - candidate;
+        if distance > FAR_MATCH_MAX_DISTANCE {
           break;
         }
-        if copy_count == 0 {
-          copy_count = u16::from_le_bytes(input.get(input_index .. (input_index+std::mem::size_of::<u16>())).ok_or(DecompressionError::CorruptFile)?.try_into().unwrap());
-          input_index += std::mem::size_of::<u16>();
+        let length = self.match_length(candidate, position);
+        let min_length = if distance > NEAR_MATCH_MAX_DISTANCE { FAR_MATCH_MIN_LENGTH } else { LONG_MATCH_MIN_LENGTH };
+        if length >= min_length && best.is_none_or(|(_, best_length)| length > best_length) {
+          best = Some((distance, length));
+          if length == LONG_MATCH_MAX_LENGTH {
+            break;
+          }
         }
-        else {
-          copy_count += 2;
+        candidate = self.previous[candidate];
+        chain_length += 1;
+      }
+    }
+
+    if best.is_none() && position + SHORT_MATCH_MIN_LENGTH <= self.input.len() {
+      let candidate = self.last_pair[self.pair(position)];
+      if candidate != usize::MAX && position - candidate <= SHORT_MATCH_MAX_DISTANCE {
+        best = Some((position - candidate, SHORT_MATCH_MIN_LENGTH));
+      }
+    }
+
+    best
+  }
+}
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+  let mut writer = InfoBitWriter::new(input.len() / 2);
+  let mut finder = MatchFinder::new(input);
+
+  // Largest amount the output has run ahead of the input, used to work out `gt_overlap`.
+  let mut max_lead: isize = 0;
+
+  let mut input_index: usize = 0;
+  while input_index < input.len() {
+    let mut found = finder.find(input_index);
+    finder.insert(input_index);
+
+    // Lazy matching: emit a literal instead if the next position has a clearly longer match.
+    if let Some((_, length)) = found {
+      if length < SHORT_MATCH_MAX_LENGTH && input_index + 1 < input.len() {
+        if let Some((_, next_length)) = finder.find(input_index + 1) {
+          if next_length > length + 1 {
+            found = None;
+          }
+        }
+      }
+    }
+
+    match found {
+      None => {
+        writer.literal(input[input_index]);
+        input_index += 1;
+      }
+      Some((distance, length)) => {
+        writer.copy(distance, length);
+        for position in input_index + 1 .. input_index + length {
+          finder.insert(position);
         }
+        input_index += length;
+      }
+    }
+    // The info word reserved at the end of a token is read after the token is written.
+    let read_index = writer.output.len() - std::mem::size_of::<u32>();
+    max_lead = max_lead.max(input_index as isize - read_index as isize);
+  }
+
+  writer.end();
+  let mut output = writer.output;
+
+  let gt_uncompressed_size = input.len() as u32;
+  let gt_overlap = (max_lead + output.len() as isize - input.len() as isize).max(0) as u32;
+  let gt_skip: u32 = 0;
+
+  let mut header = Vec::with_capacity(std::mem::size_of::<GTHeader>());
+  header.extend_from_slice(GT_MAGIC);
+  header.extend_from_slice(&gt_uncompressed_size.to_le_bytes());
+  header.extend_from_slice(&gt_overlap.to_le_bytes());
+  header.extend_from_slice(&gt_skip.to_le_bytes());
+  output[.. header.len()].copy_from_slice(&header);
+
+  output
+}
+
+// Back references reach at most this far back: a long match offset of 0 plus the far flag.
+const WINDOW_SIZE: usize = FAR_MATCH_MAX_DISTANCE;
+
+// Decompresses GT20 data as it is read, keeping only the last `WINDOW_SIZE` bytes of output for
+// back references instead of the whole output. Produces the same bytes as `decompress`. Reads
+// the input a few bytes at a time, so unbuffered readers are best wrapped in a `BufReader`.
+// Errors are `io::Error`s of kind `InvalidData`, or `UnexpectedEof` for truncated data, that
+// wrap the crate `Error` with the offset of the failure.
+pub struct GtDecoder<R> {
+  reader: R,
+  header: GTHeader,
+  window: Vec<u8>,
+  info_bits: u32,
+  info_count: u16,
+  input_index: usize,
+  output_index: usize,
+  // Distance and remaining length of the back reference being copied.
+  copy: Option<(usize, usize)>,
+  finished: bool
+}
+
+impl<R: Read> GtDecoder<R> {
+  // Reads the GT20 header, skips to the GT data and reads the first info word.
+  pub fn new(mut reader: R) -> io::Result<Self> {
+    let mut header_bytes = [0; std::mem::size_of::<GTHeader>()];
+    reader.read_exact(&mut header_bytes).map_err(|e| eof_error(e, 0))?;
+    let header = parse_header(&header_bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    // The GT data starts `gt_skip` bytes after the header.
+    let skipped = io::copy(&mut (&mut reader).take(header.gt_skip.into()), &mut io::sink())?;
+    if skipped < header.gt_skip.into() {
+      return Err(eof_error(io::ErrorKind::UnexpectedEof.into(), header_bytes.len() + skipped as usize));
+    }
+
+    let mut decoder = Self {
+      reader,
+      header,
+      window: vec![0; WINDOW_SIZE],
+      info_bits: 0,
+      info_count: 0,
+      input_index: header_bytes.len() + header.gt_skip as usize,
+      output_index: 0,
+      copy: None,
+      finished: false
+    };
+    decoder.read_info_bits()?;
+    Ok(decoder)
+  }
+
+  pub fn header(&self) -> &GTHeader {
+    &self.header
+  }
+
+  // Size of the output, from the header.
+  pub fn uncompressed_size(&self) -> usize {
+    self.header.gt_uncompressed_size as usize
+  }
+
+  pub fn into_inner(self) -> R {
+    self.reader
+  }
+
+  fn read_bytes<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    self.reader.read_exact(&mut bytes).map_err(|e| eof_error(e, self.input_index))?;
+    self.input_index += N;
+    Ok(bytes)
+  }
+
+  fn read_info_bits(&mut self) -> io::Result<()> {
+    self.info_bits = u32::from_le_bytes(self.read_bytes()?);
+    self.info_count = 32;
+    Ok(())
+  }
+
+  fn next_info_bit(&mut self) -> io::Result<()> {
+    self.info_bits >>= 1;
+    self.info_count -= 1;
+    if self.info_count == 0 {
+      self.read_info_bits()?;
+    }
+    Ok(())
+  }
+
+  fn push(&mut self, value: u8) -> io::Result<u8> {
+    if self.output_index >= self.uncompressed_size() {
+      return Err(invalid_data(self.input_index, "decompressed data is larger than the expected size"));
+    }
+    self.window[self.output_index % WINDOW_SIZE] = value;
+    self.output_index += 1;
+    Ok(value)
+  }
+
+  fn start_copy(&mut self, distance: usize, length: usize) -> io::Result<()> {
+    if length == 0 {
+      return Ok(());
+    }
+    if distance > self.output_index {
+      return Err(invalid_data(self.input_index, "back reference before the start of the data"));
+    }
+    self.copy = Some((distance, length));
+    Ok(())
+  }
+
+  // Reads the next token. Literals are returned, back references are left in `copy`.
+  fn next_token(&mut self) -> io::Result<Option<u8>> {
+    if self.info_bits & 1 == 0 {
+      let [value] = self.read_bytes()?;
+      self.next_info_bit()?;
+      return self.push(value).map(Some);
+    }
+    self.next_info_bit()?;
+
+    if self.info_bits & 1 != 0 {
+      let word = u16::from_le_bytes(self.read_bytes()?);
+      let mut distance = NEAR_MATCH_MAX_DISTANCE - (word >> 3) as usize;
+      let mut length = (word & 7) as usize;
+      if length != 0 {
+        length += 2;
       }
-      
-      while copy_count!=0 {
-        COPY_ARRAY_VALUE!(output, output_index, output, copy_index);
-        output_index += 1;
-        copy_index += 1;
-        
-        copy_count -= 1;
+      else {
+        let [extra] = self.read_bytes()?;
+        if extra & 128 != 0 {
+          distance += NEAR_MATCH_MAX_DISTANCE;
+        }
+        length = match extra & 127 {
+          1 => {
+            self.finished = true;
+            return Ok(None);
+          }
+          0 => u16::from_le_bytes(self.read_bytes()?) as usize,
+          count => count as usize + 2
+        };
       }
+      self.start_copy(distance, length)?;
     }
     else {
-      let tmp = (*input.get(input_index).ok_or(DecompressionError::CorruptFile)? as u32).wrapping_sub(256);
+      let [offset] = self.read_bytes()?;
+      let mut length = SHORT_MATCH_MIN_LENGTH;
+      self.next_info_bit()?;
+      if self.info_bits & 1 != 0 {
+        length += 2;
+      }
+      self.next_info_bit()?;
+      if self.info_bits & 1 != 0 {
+        length += 1;
+      }
+      self.start_copy(SHORT_MATCH_MAX_DISTANCE - offset as usize, length)?;
+    }
+    self.next_info_bit()?;
+    Ok(None)
+  }
+}
+
+impl<R: Read> Read for GtDecoder<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+      if let Some((distance, length)) = self.copy {
+        let value = self.window[(self.output_index - distance) % WINDOW_SIZE];
+        buf[filled] = self.push(value)?;
+        filled += 1;
+        self.copy = if length > 1 { Some((distance, length - 1)) } else { None };
+      }
+      else if self.finished {
+        // Like `decompress`, output that ends early is padded with zeros to the expected size.
+        if self.output_index >= self.uncompressed_size() {
+          break;
+        }
+        buf[filled] = self.push(0)?;
+        filled += 1;
+      }
+      else if let Some(value) = self.next_token()? {
+        buf[filled] = value;
+        filled += 1;
+      }
+    }
+    Ok(filled)
+  }
+}
+
+fn invalid_data(offset: usize, reason: &'static str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, Error::invalid_data(Format::GT20, offset, reason))
+}
+
+fn eof_error(error: io::Error, offset: usize) -> io::Error {
+  match error.kind() {
+    io::ErrorKind::UnexpectedEof => io::Error::new(io::ErrorKind::UnexpectedEof, Error::truncated(Format::GT20, offset)),
+    _ => error
+  }
+}
 
-      copy_index = ((output_index as u32).wrapping_add(tmp)) as usize;
-      input_index += 1;
+#[cfg(test)]
+mod tests {
+  use super::*;
 
-      COPY_ARRAY_VALUE!(output, output_index, output, copy_index);
-      output_index += 1;
-      copy_index += 1;
+  // Small xorshift generator, so the inputs are the same on every run.
+  struct Random(u64);
 
-      COPY_ARRAY_VALUE!(output, output_index, output, copy_index);
-      output_index += 1;
-      copy_index += 1;
+  impl Random {
+    fn next(&mut self) -> u64 {
+      self.0 ^= self.0 << 13;
+      self.0 ^= self.0 >> 7;
+      self.0 ^= self.0 << 17;
+      self.0
+    }
 
-      NEXTINFOBIT!(input, info_bits, input_index, info_count);
+    fn below(&mut self, bound: usize) -> usize {
+      (self.next() % bound as u64) as usize
+    }
 
-      if (info_bits & 1) != 0 {
-        COPY_ARRAY_VALUE!(output, output_index, output, copy_index);
-        output_index += 1;
-        copy_index += 1;
-        
-        COPY_ARRAY_VALUE!(output, output_index, output, copy_index);
-        output_index += 1;
-        copy_index += 1;
+    fn bytes(&mut self, length: usize) -> Vec<u8> {
+      (0 .. length).map(|_| self.next() as u8).collect()
+    }
+  }
+
+  // Random data mixed with runs and repeats of earlier data, from every distance the format reaches.
+  fn test_input(random: &mut Random) -> Vec<u8> {
+    let length = random.below(6000);
+    let mut input = Vec::with_capacity(length);
+    while input.len() < length {
+      match random.below(4) {
+        0 => {
+          let length = random.below(64) + 1;
+          input.extend(random.bytes(length));
+        }
+        1 => {
+          let (value, length) = (random.next() as u8, random.below(300) + 1);
+          input.extend(vec![value; length]);
+        }
+        _ if !input.is_empty() => {
+          let start = random.below(input.len());
+          let end = (start + random.below(200) + 1).min(input.len());
+          input.extend_from_within(start .. end);
+        }
+        _ => {}
       }
-      
-      NEXTINFOBIT!(input, info_bits, input_index, info_count);
+    }
+    input.truncate(length);
+    input
+  }
 
-      if (info_bits & 1) != 0 {
-        COPY_ARRAY_VALUE!(output, output_index, output, copy_index);
-        output_index += 1;
+  fn test_inputs() -> Vec<Vec<u8>> {
+    let mut random = Random(0x2545_f491_4f6c_dd1d);
+    (0 .. 400).map(|_| test_input(&mut random)).collect()
+  }
+
+  #[test]
+  fn compress_round_trips_through_decompress() {
+    for input in test_inputs() {
+      let compressed = compress(&input);
+      assert_eq!(parse_header(&compressed).unwrap().gt_uncompressed_size as usize, input.len());
+      assert_eq!(decompress(&compressed, input.len() as u32).unwrap(), input);
+    }
+  }
+
+  #[test]
+  fn compress_round_trips_empty_input() {
+    let compressed = compress(&[]);
+    assert_eq!(parse_header(&compressed).unwrap().gt_uncompressed_size, 0);
+    assert_eq!(decompress(&compressed, 0).unwrap(), Vec::<u8>::new());
+  }
+
+  #[test]
+  fn compress_round_trips_runs_longer_than_a_match() {
+    let mut input = vec![0x55; LONG_MATCH_MAX_LENGTH * 3 + 17];
+    input.extend(vec![0xaa; LONG_MATCH_MAX_LENGTH + 1]);
+    let compressed = compress(&input);
+    assert!(compressed.len() < 100);
+    assert_eq!(decompress(&compressed, input.len() as u32).unwrap(), input);
+  }
+
+  #[test]
+  fn compress_uses_far_matches() {
+    let mut random = Random(7);
+    let block = random.bytes(0x400);
+    let mut input = block.clone();
+    input.extend(random.bytes(0x3000));
+    input.extend_from_slice(&block);
+
+    let compressed = compress(&input);
+    assert_eq!(decompress(&compressed, input.len() as u32).unwrap(), input);
+    let trace = trace(&compressed, input.len() as u32).unwrap();
+    assert!(trace.error.is_none());
+    assert!(trace.tokens.iter().any(|token| matches!(token.kind,
+      TokenKind::LongMatch { distance, far: true, .. } if distance > NEAR_MATCH_MAX_DISTANCE && distance <= FAR_MATCH_MAX_DISTANCE)));
+  }
+
+  // Reads `decoder` a few bytes at a time, so copies are split across reads.
+  fn read_in_pieces<R: Read>(mut decoder: GtDecoder<R>, random: &mut Random) -> io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut piece = [0; 7];
+    loop {
+      let length = random.below(piece.len()) + 1;
+      match decoder.read(&mut piece[.. length])? {
+        0 => return Ok(output),
+        read => output.extend_from_slice(&piece[.. read])
       }
     }
   }
 
-    NEXTINFOBIT!(input, info_bits, input_index, info_count);
+  #[test]
+  fn gt_decoder_matches_decompress() {
+    let mut random = Random(99);
+    for input in test_inputs() {
+      let compressed = compress(&input);
+      let decoder = GtDecoder::new(&compressed[..]).unwrap();
+      assert_eq!(decoder.uncompressed_size(), input.len());
+      assert_eq!(read_in_pieces(decoder, &mut random).unwrap(), input);
+    }
   }
 
-  Ok(output)
-}
\ No newline at end of file
+  #[test]
+  fn gt_decoder_reports_truncated_data() {
+    let mut random = Random(3);
+    let input = test_input(&mut random);
+    let compressed = compress(&input);
+    let truncated = &compressed[.. compressed.len() - 3];
+
+    let mut output = Vec::new();
+    let error = GtDecoder::new(truncated).unwrap().read_to_end(&mut output).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    let error = error.into_inner().unwrap().downcast::<Error>().unwrap();
+    assert_eq!(*error, Error::truncated(Format::GT20, truncated.len()));
+  }
+
+  // `compressed` with `skip` filler bytes between the header and the GT data, and the overlap
+  // grown to fit them.
+  fn with_skip(compressed: &[u8], skip: u32) -> Vec<u8> {
+    let header_size = std::mem::size_of::<GTHeader>();
+    let overlap = u32::from_le_bytes(compressed[8 .. 12].try_into().unwrap());
+    let mut skipped = compressed[.. header_size].to_vec();
+    skipped[8 .. 12].copy_from_slice(&(overlap + skip).to_le_bytes());
+    skipped[12 .. 16].copy_from_slice(&skip.to_le_bytes());
+    skipped.extend(vec![0xee; skip as usize]);
+    skipped.extend_from_slice(&compressed[header_size ..]);
+    skipped
+  }
+
+  #[test]
+  fn decompression_honors_gt_skip() {
+    let mut random = Random(11);
+    for _ in 0 .. 50 {
+      let input = test_input(&mut random);
+      let skipped = with_skip(&compress(&input), random.below(40) as u32 + 1);
+      assert_eq!(decompress(&skipped, input.len() as u32).unwrap(), input);
+      let mut output = Vec::new();
+      GtDecoder::new(&skipped[..]).unwrap().read_to_end(&mut output).unwrap();
+      assert_eq!(output, input);
+      assert_eq!(decompress_with_overlap(&skipped, input.len() as u32).unwrap(), input);
+    }
+  }
+
+  #[test]
+  fn compress_overlap_allows_in_place_decompression() {
+    for input in test_inputs() {
+      let compressed = compress(&input);
+      assert_eq!(decompress_with_overlap(&compressed, input.len() as u32).unwrap(), input);
+
+      let header = parse_header(&compressed).unwrap();
+      let mut buffer = vec![0xcc; in_place_buffer_size(&header).max(compressed.len())];
+      let file_start = buffer.len() - compressed.len();
+      buffer[file_start ..].copy_from_slice(&compressed);
+      assert_eq!(decompress_in_place(&mut buffer, compressed.len()).unwrap(), input.len());
+      assert_eq!(buffer[.. input.len()], input[..]);
+    }
+  }
+
+  #[test]
+  fn in_place_decompression_detects_a_small_overlap() {
+    let input = vec![3; 1000];
+    let mut compressed = compress(&input);
+    compressed[8 .. 12].copy_from_slice(&0_u32.to_le_bytes());
+    let error = decompress_with_overlap(&compressed, input.len() as u32).unwrap_err();
+    assert_eq!(error, Error::invalid_data(Format::GT20, error.offset(), "overlap is too small to decompress in place"));
+  }
+
+  #[test]
+  fn sizes_the_data_cannot_hold_are_rejected_before_allocating() {
+    let mut compressed = compress(b"hello hello hello");
+    compressed[4 .. 8].copy_from_slice(&u32::MAX.to_le_bytes());
+    compressed[8 .. 12].copy_from_slice(&u32::MAX.to_le_bytes());
+    let expected = Error::invalid_data(Format::GT20, 4, "uncompressed size is larger than the compressed data can hold");
+    assert_eq!(decompress(&compressed, u32::MAX).unwrap_err(), expected);
+    assert_eq!(trace(&compressed, u32::MAX).unwrap_err(), expected);
+    assert_eq!(decompress_with_overlap(&compressed, u32::MAX).unwrap_err(), expected);
+    assert!(decompress_with_overlap(&compressed, 17).is_err());
+  }
+}
@@ -1,4 +1,9 @@
-use nom::{IResult, multi::many0, number::complete::le_u32};
+use std::convert::TryFrom;
+
+use nom::{multi::many0, number::complete::le_u32};
+
+use crate::error::{Error, ParseResult};
+use super::{gt, Format};
 
 #[derive(Debug, Default, PartialEq, Clone, Copy)]
 pub struct IdxRecord {
@@ -8,7 +13,7 @@ pub struct IdxRecord {
   pub unused : u32
 }
 
-fn record(input: &[u8]) -> IResult<&[u8], IdxRecord> {
+fn record(input: &[u8]) -> ParseResult<'_, IdxRecord> {
   let (input, o) = nom::sequence::tuple((le_u32, le_u32, le_u32, le_u32))(input)?;
   let (file_offset, compressed_file_length, decompressed_file_length, unused) = o;
   Ok(
@@ -16,7 +21,98 @@ fn record(input: &[u8]) -> IResult<&[u8], IdxRecord> {
   )
 }
 
-pub fn parse_records(input: &[u8]) -> IResult<&[u8], Vec<IdxRecord>> {
-  let x= many0(record)(input)?.1;
-  Ok((input, x))
-}
\ No newline at end of file
+// Trailing bytes that do not make up a whole record are ignored.
+pub fn parse_records(input: &[u8]) -> Result<Vec<IdxRecord>, Error> {
+  let x= many0(record)(input).map_err(|e| Error::from_parse(input, Format::Unknown, e))?.1;
+  Ok(x)
+}
+
+impl IdxRecord {
+  pub fn to_bytes(&self) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    bytes[0..4].copy_from_slice(&self.file_offset.to_le_bytes());
+    bytes[4..8].copy_from_slice(&self.compressed_file_length.to_le_bytes());
+    bytes[8..12].copy_from_slice(&self.decompressed_file_length.to_le_bytes());
+    bytes[12..16].copy_from_slice(&self.unused.to_le_bytes());
+    bytes
+  }
+}
+
+pub fn write_records(records: &[IdxRecord]) -> Vec<u8> {
+  records.iter().flat_map(|record| record.to_bytes()).collect()
+}
+
+// Builds a new idx/img pair one entry at a time, in record order.
+#[derive(Debug, Default)]
+pub struct Packer {
+  records: Vec<IdxRecord>,
+  img: Vec<u8>
+}
+
+impl Packer {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  // Appends `data` to the img, GT20 compressing it first if asked to. Data that is already
+  // GT20 compressed is stored as is, with the decompressed length taken from its header. Fails
+  // when the record's offset or lengths do not fit the 32 bits the idx stores them in.
+  pub fn add(&mut self, data: &[u8], compress: bool) -> Result<IdxRecord, Error> {
+    let entry = self.records.len();
+    let too_large = |reason| Error::invalid_data(Format::Unknown, 0, reason).with_entry(entry);
+    let data_length = || u32::try_from(data.len()).map_err(|_| too_large("record is too large for an idx record"));
+    let (stored, decompressed_file_length) = match gt::parse_header(data) {
+      Ok(header) => (data.to_vec(), header.gt_uncompressed_size),
+      Err(_) if compress => (gt::compress(data), data_length()?),
+      Err(_) => (data.to_vec(), data_length()?)
+    };
+
+    let record = IdxRecord {
+      file_offset: u32::try_from(self.img.len()).map_err(|_| too_large("record starts past the 4 GiB an idx record can refer to"))?,
+      compressed_file_length: u32::try_from(stored.len()).map_err(|_| too_large("record is too large for an idx record"))?,
+      decompressed_file_length,
+      unused: 0
+    };
+    self.img.extend_from_slice(&stored);
+    self.records.push(record);
+    Ok(record)
+  }
+
+  // Returns the serialized (idx, img) pair.
+  pub fn finish(self) -> (Vec<u8>, Vec<u8>) {
+    (write_records(&self.records), self.img)
+  }
+}
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::archive::Archive;
+
+  #[test]
+  fn packed_records_read_back_through_an_archive() {
+    let files: Vec<Vec<u8>> = vec![
+      b"BTP plain record".to_vec(),
+      (0 .. 3000).map(|i| (i % 7) as u8).collect(),
+      Vec::new(),
+      gt::compress(b"already compressed, already compressed")
+    ];
+    let mut packer = Packer::new();
+    let records: Vec<IdxRecord> = files.iter().enumerate()
+      .map(|(i, file)| packer.add(file, i == 1).unwrap())
+      .collect();
+    let (idx, img) = packer.finish();
+
+    let archive = Archive::open(&idx, &img).unwrap();
+    assert_eq!(archive.records(), &records[..]);
+    assert_eq!(archive.check_records(), []);
+    assert_eq!(archive.get(0).unwrap().format(), Format::Btp);
+    assert_eq!(archive.get(1).unwrap().format(), Format::GT20);
+    assert!(records[1].compressed_file_length < records[1].decompressed_file_length);
+    assert_eq!(records[3].decompressed_file_length, 38);
+
+    let expected = [files[0].clone(), files[1].clone(), Vec::new(), b"already compressed, already compressed".to_vec()];
+    for (entry, expected) in archive.entries().zip(expected.iter()) {
+      assert_eq!(&entry.decoded_bytes().unwrap()[..], &expected[..]);
+    }
+  }
+}
@@ -1,41 +1,66 @@
 use std::fmt::Display;
 
+use nom::{Parser, multi::count};
+
+use crate::error::{FailureKind, ParseFailure, ParseResult};
+
 pub mod idx;
 pub mod btp;
 pub mod gfxm;
+pub mod modl;
 pub mod gt;
 mod bitmap;
 
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Format {
     Btp,
     Bitmap,
     Gfxm,
+    Modl,
     GT20,
     Unknown
 }
 
 impl Display for Format {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-      let format_string: &str;
-      match self {
-          Format::Btp => format_string = "btp",
-          Format::Bitmap => format_string = "bmp",
-          Format::Gfxm => format_string = "gfxm",
-          Format::GT20 => format_string = "gt20",
-          Format::Unknown => format_string = "",
-      }
+      let format_string = match self {
+          Format::Btp => "btp",
+          Format::Bitmap => "bmp",
+          Format::Gfxm => "gfxm",
+          Format::Modl => "modl",
+          Format::GT20 => "gt20",
+          Format::Unknown => "",
+      };
       write!(f, "{}", format_string)?;
       Ok(())
     }
 }
 
+// The longest signature. `identify_format` reads no further than this into the data.
+pub const MAX_SIGNATURE_LENGTH: usize = 4;
+
 pub fn identify_format(input: &[u8]) -> Format {
   if btp::parse_magic(input).is_ok() { return Format::Btp };
   if bitmap::parse_magic(input).is_ok() { return Format::Bitmap};
   if gfxm::parse_magic(input).is_ok() { return Format::Gfxm}
+  if modl::parse_magic(input).is_ok() { return Format::Modl}
   if gt::parse_magic(input).is_ok() { return Format::GT20}
   
   Format::Unknown
+}
+
+// Like `nom::multi::count`, but fails before allocating when `input` is too short to hold
+// `num_items` items of `item_size` bytes, as `count` reserves space for every item up front.
+pub(crate) fn count_sized<'a, O, F>(parser: F, num_items: usize, item_size: usize) -> impl FnMut(&'a [u8]) -> ParseResult<'a, Vec<O>>
+where
+  F: Parser<&'a [u8], O, ParseFailure<'a>>
+{
+  let mut parser = count(parser, num_items);
+  move |input: &'a [u8]| {
+    match num_items.checked_mul(item_size) {
+      Some(size) if size <= input.len() => parser(input),
+      _ => Err(nom::Err::Error(ParseFailure { input, kind: FailureKind::Truncated }))
+    }
+  }
 }
\ No newline at end of file
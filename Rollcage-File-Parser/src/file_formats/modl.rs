@@ -1,3 +1,14 @@
+use std::convert::TryInto;
+
+use nom::{
+    bytes::complete::{tag, take},
+    multi::count,
+    number::complete::{le_i16, le_u16, le_u32, le_u8},
+};
+
+use super::Format;
+use crate::error::{invalid_data, seek, Error, ParseResult};
+
 const MODL_MAGIC: &[u8; 4] = &[0x4D, 0x4F, 0x44, 0x4C]; // "MODL"
 
 #[derive(Debug, Clone, Copy)]
@@ -14,4 +25,348 @@ pub struct ModlHeader {
     pub offset_to_sub_model_2: u32,
     pub unknown_2: [u8;36],
     pub offset_to_unknown_data_3: u32 // from current modl
-}
\ No newline at end of file
+}
+
+pub const MODL_HEADER_SIZE: usize = 76;
+
+// How deep sub-models may nest below the MODL being parsed.
+const MAX_SUB_MODEL_DEPTH: usize = 32;
+
+// How many MODLs one MODL and its sub-models may add up to. A sub-model used more than once counts
+// every time, as it is parsed again for each use.
+const MAX_TREE_MODLS: usize = 4096;
+
+// Vertices directly follow the header.
+#[derive(Debug, Clone, Copy)]
+pub struct Vertex {
+    pub x: i16,
+    pub y: i16,
+    pub z: i16,
+    pub padding: i16,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Face {
+    pub vertex_indices: [u16; 4], // triangles repeat their last index
+    pub texture_page: u16,
+    pub flags: u16,
+    pub uvs: [[u8; 2]; 4],
+}
+
+impl Face {
+    pub fn is_triangle(&self) -> bool {
+        self.vertex_indices[3] == self.vertex_indices[2]
+    }
+
+    pub fn num_vertices(&self) -> usize {
+        if self.is_triangle() {
+            3
+        } else {
+            4
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Modl {
+    pub header: ModlHeader,
+    pub vertices: Vec<Vertex>,
+    pub faces: Vec<Face>,
+    pub face_indices: Vec<u16>,
+    pub sub_models: Vec<Modl>,
+}
+
+pub(crate) fn parse_magic(input: &[u8]) -> ParseResult<'_, &[u8; MODL_MAGIC.len()]> {
+    let (input, signature) = tag(MODL_MAGIC)(input)?;
+    Ok((input, signature.try_into().unwrap()))
+}
+
+fn modl_header(input: &[u8]) -> ParseResult<'_, ModlHeader> {
+    let (input, signature) = parse_magic(input)?;
+    let (input, num_veritices) = le_u16(input)?;
+    let (input, num_faces) = le_u16(input)?;
+    let (input, unknown_1) = le_u32(input)?;
+    let (input, offset_to_face_data) = le_u32(input)?;
+    let (input, offset_to_unknown_data_1) = le_u32(input)?;
+    let (input, offset_to_face_data_index) = le_u32(input)?;
+    let (input, offset_to_unknown_data_2) = le_u32(input)?;
+    let (input, offset_to_sub_model_1) = le_u32(input)?;
+    let (input, offset_to_sub_model_2) = le_u32(input)?;
+    let (input, unknown_2) = take(36_usize)(input)?;
+    let (input, offset_to_unknown_data_3) = le_u32(input)?;
+
+    Ok((
+        input,
+        ModlHeader {
+            signature: *signature,
+            num_veritices,
+            num_faces,
+            unknown_1,
+            offset_to_face_data,
+            offset_to_unknown_data_1,
+            offset_to_face_data_index,
+            offset_to_unknown_data_2,
+            offset_to_sub_model_1,
+            offset_to_sub_model_2,
+            unknown_2: unknown_2.try_into().unwrap(),
+            offset_to_unknown_data_3,
+        },
+    ))
+}
+
+fn parse_vertex(input: &[u8]) -> ParseResult<'_, Vertex> {
+    let (input, (x, y, z, padding)) =
+        nom::sequence::tuple((le_i16, le_i16, le_i16, le_i16))(input)?;
+    Ok((input, Vertex { x, y, z, padding }))
+}
+
+fn parse_face(input: &[u8]) -> ParseResult<'_, Face> {
+    let (input, vertex_indices) = count(le_u16, 4)(input)?;
+    let (input, texture_page) = le_u16(input)?;
+    let (input, flags) = le_u16(input)?;
+    let (input, uvs) = count(nom::sequence::tuple((le_u8, le_u8)), 4)(input)?;
+
+    let mut face = Face {
+        vertex_indices: [0; 4],
+        texture_page,
+        flags,
+        uvs: [[0; 2]; 4],
+    };
+    face.vertex_indices.copy_from_slice(&vertex_indices);
+    for (uv, (u, v)) in face.uvs.iter_mut().zip(uvs) {
+        *uv = [u, v];
+    }
+    Ok((input, face))
+}
+
+fn vertices(input: &[u8], modl_offset: usize, num_vertices: usize) -> ParseResult<'_, Vec<Vertex>> {
+    count(parse_vertex, num_vertices)(seek(input, modl_offset + MODL_HEADER_SIZE)?)
+}
+
+fn faces(input: &[u8], face_data_offset: usize, num_faces: usize) -> ParseResult<'_, Vec<Face>> {
+    count(parse_face, num_faces)(seek(input, face_data_offset)?)
+}
+
+fn face_indices(input: &[u8], face_data_index_offset: usize, num_faces: usize) -> ParseResult<'_, Vec<u16>> {
+    count(le_u16, num_faces)(seek(input, face_data_index_offset)?)
+}
+
+// Offsets of the sub-models, from current modl. Zero means there is no sub-model.
+pub fn sub_model_offsets(header: &ModlHeader) -> Vec<u32> {
+    [header.offset_to_sub_model_1, header.offset_to_sub_model_2]
+        .iter()
+        .copied()
+        .filter(|offset| *offset != 0)
+        .collect()
+}
+
+// `path` holds the offsets of the MODLs this one is a sub-model of, so a sub-model that contains
+// itself is rejected. Sub-models may be shared, and `num_modls` counts every MODL parsed for the
+// tree so that sharing cannot make the work grow exponentially with the depth.
+fn modl<'a>(
+    input: &'a [u8],
+    modl_offset: usize,
+    section_offset: usize,
+    path: &mut Vec<usize>,
+    num_modls: &mut usize,
+) -> ParseResult<'a, Modl> {
+    let modl_input = seek(input, modl_offset)?;
+    if path.contains(&modl_offset) {
+        return Err(invalid_data(modl_input, "sub-model contains itself"));
+    }
+    if path.len() > MAX_SUB_MODEL_DEPTH {
+        return Err(invalid_data(modl_input, "sub-models are nested too deeply"));
+    }
+    *num_modls += 1;
+    if *num_modls > MAX_TREE_MODLS {
+        return Err(invalid_data(modl_input, "modl has too many sub-models"));
+    }
+    let header = modl_header(modl_input)?.1;
+
+    let vertices = vertices(input, modl_offset, header.num_veritices as usize)?.1;
+    let faces = faces(
+        input,
+        modl_offset + header.offset_to_face_data as usize,
+        header.num_faces as usize,
+    )?
+    .1;
+    let face_indices = face_indices(
+        input,
+        section_offset + header.offset_to_face_data_index as usize,
+        header.num_faces as usize,
+    )?
+    .1;
+
+    let mut sub_models = Vec::new();
+    path.push(modl_offset);
+    for offset in sub_model_offsets(&header) {
+        let sub_model_offset = modl_offset + offset as usize;
+        let sub_model = modl(input, sub_model_offset, section_offset, path, num_modls)?.1;
+        sub_models.push(sub_model);
+    }
+    path.pop();
+
+    Ok((
+        input,
+        Modl {
+            header,
+            vertices,
+            faces,
+            face_indices,
+            sub_models,
+        },
+    ))
+}
+
+pub fn parse_modl_header(input: &[u8]) -> Result<ModlHeader, Error> {
+    modl_header(input)
+        .map(|(_, header)| header)
+        .map_err(|e| Error::from_parse(input, Format::Modl, e))
+}
+
+pub fn parse_vertices(input: &[u8], modl_offset: usize, num_vertices: usize) -> Result<Vec<Vertex>, Error> {
+    vertices(input, modl_offset, num_vertices)
+        .map(|(_, vertices)| vertices)
+        .map_err(|e| Error::from_parse(input, Format::Modl, e))
+}
+
+pub fn parse_faces(input: &[u8], face_data_offset: usize, num_faces: usize) -> Result<Vec<Face>, Error> {
+    faces(input, face_data_offset, num_faces)
+        .map(|(_, faces)| faces)
+        .map_err(|e| Error::from_parse(input, Format::Modl, e))
+}
+
+pub fn parse_face_indices(input: &[u8], face_data_index_offset: usize, num_faces: usize) -> Result<Vec<u16>, Error> {
+    face_indices(input, face_data_index_offset, num_faces)
+        .map(|(_, face_indices)| face_indices)
+        .map_err(|e| Error::from_parse(input, Format::Modl, e))
+}
+
+// Parses the MODL at `modl_offset` in `input`. `section_offset` is the start of the modl
+// section it belongs to, which the face index data is relative to.
+pub fn parse_modl(input: &[u8], modl_offset: usize, section_offset: usize) -> Result<Modl, Error> {
+    modl(input, modl_offset, section_offset, &mut Vec::new(), &mut 0)
+        .map(|(_, modl)| modl)
+        .map_err(|e| Error::from_parse(input, Format::Modl, e))
+}
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) const FACE_SIZE: usize = 20;
+
+    // A MODL with `num_vertices` vertices numbered from `first`, triangles over them and the
+    // given sub-model offsets. Its faces follow the vertices and its face indices follow the faces,
+    // at `face_index_offset` from the start of the modl section.
+    pub(crate) fn modl_bytes(
+        first: i16,
+        num_vertices: u16,
+        num_faces: u16,
+        face_index_offset: u32,
+        sub_models: [u32; 2],
+    ) -> Vec<u8> {
+        let face_data_offset = MODL_HEADER_SIZE + num_vertices as usize * 8;
+        let mut bytes = MODL_MAGIC.to_vec();
+        bytes.extend_from_slice(&num_vertices.to_le_bytes());
+        bytes.extend_from_slice(&num_faces.to_le_bytes());
+        bytes.extend_from_slice(&0_u32.to_le_bytes());
+        bytes.extend_from_slice(&(face_data_offset as u32).to_le_bytes());
+        bytes.extend_from_slice(&0_u32.to_le_bytes());
+        bytes.extend_from_slice(&face_index_offset.to_le_bytes());
+        bytes.extend_from_slice(&0_u32.to_le_bytes());
+        bytes.extend_from_slice(&sub_models[0].to_le_bytes());
+        bytes.extend_from_slice(&sub_models[1].to_le_bytes());
+        bytes.extend_from_slice(&[0; 36]);
+        bytes.extend_from_slice(&0_u32.to_le_bytes());
+        for i in 0..num_vertices as i16 {
+            for value in [first + i, -(first + i), 2 * i, 0].iter() {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        for face in 0..num_faces {
+            let corner = |i: u16| (face + i) % num_vertices.max(1);
+            let corners = [corner(0), corner(1), corner(2), corner(2)];
+            for index in corners.iter() {
+                bytes.extend_from_slice(&index.to_le_bytes());
+            }
+            bytes.extend_from_slice(&face.to_le_bytes());
+            bytes.extend_from_slice(&0_u16.to_le_bytes());
+            bytes.extend_from_slice(&[0, 0, 8, 0, 8, 8, 0, 8]);
+        }
+        bytes
+    }
+
+    // Face index data for `num_faces` faces, counting down from `num_faces`.
+    fn face_index_bytes(num_faces: u16) -> Vec<u8> {
+        (1..=num_faces).rev().flat_map(|index| index.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn parses_vertices_faces_and_face_indices() {
+        let mut input = vec![0xee; 6];
+        let modl_offset = input.len();
+        let face_index_offset = MODL_HEADER_SIZE + 3 * 8 + 2 * FACE_SIZE;
+        input.extend(modl_bytes(5, 3, 2, face_index_offset as u32, [0, 0]));
+        input.extend(face_index_bytes(2));
+
+        let modl = parse_modl(&input, modl_offset, modl_offset).unwrap();
+        assert_eq!((modl.header.num_veritices, modl.header.num_faces), (3, 2));
+        let vertices: Vec<_> = modl.vertices.iter().map(|v| (v.x, v.y, v.z)).collect();
+        assert_eq!(vertices, [(5, -5, 0), (6, -6, 2), (7, -7, 4)]);
+        assert_eq!(modl.faces[1].vertex_indices, [1, 2, 0, 0]);
+        assert_eq!(modl.faces[1].texture_page, 1);
+        assert_eq!(modl.faces[1].uvs, [[0, 0], [8, 0], [8, 8], [0, 8]]);
+        assert_eq!(modl.faces[1].num_vertices(), 3);
+        assert_eq!(modl.face_indices, [2, 1]);
+        assert!(modl.sub_models.is_empty());
+    }
+
+    #[test]
+    fn shared_sub_models_are_parsed_for_every_use() {
+        let parent_length = MODL_HEADER_SIZE + 8;
+        let mut input = modl_bytes(0, 1, 0, 0, [parent_length as u32, parent_length as u32]);
+        input.extend(modl_bytes(40, 2, 0, 0, [0, 0]));
+
+        let modl = parse_modl(&input, 0, 0).unwrap();
+        assert_eq!(modl.sub_models.len(), 2);
+        for sub_model in modl.sub_models.iter() {
+            assert_eq!(sub_model.vertices[1].x, 41);
+        }
+    }
+
+    // A chain of `length` MODLs whose sub-model offsets both point at the next one.
+    fn sub_model_chain(length: usize) -> Vec<u8> {
+        let mut input = Vec::new();
+        for i in 0..length {
+            let next = if i + 1 < length { MODL_HEADER_SIZE as u32 } else { 0 };
+            input.extend(modl_bytes(0, 0, 0, 0, [next, next]));
+        }
+        input
+    }
+
+    #[test]
+    fn sub_model_trees_are_bounded() {
+        let modl = parse_modl(&sub_model_chain(12), 0, 0).unwrap();
+        assert_eq!(modl.sub_models[1].sub_models.len(), 2);
+
+        let error = parse_modl(&sub_model_chain(20), 0, 0).unwrap_err();
+        assert_eq!(
+            error,
+            Error::invalid_data(Format::Modl, error.offset(), "modl has too many sub-models")
+        );
+
+        let mut input = Vec::new();
+        for _ in 0..MAX_SUB_MODEL_DEPTH + 2 {
+            input.extend(modl_bytes(0, 0, 0, 0, [MODL_HEADER_SIZE as u32, 0]));
+        }
+        let error = parse_modl(&input, 0, 0).unwrap_err();
+        assert_eq!(
+            error,
+            Error::invalid_data(
+                Format::Modl,
+                MODL_HEADER_SIZE * (MAX_SUB_MODEL_DEPTH + 1),
+                "sub-models are nested too deeply"
+            )
+        );
+    }
+}
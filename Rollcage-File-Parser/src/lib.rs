@@ -1 +1,6 @@
-pub mod file_formats;
\ No newline at end of file
+pub mod archive;
+pub mod error;
+pub mod export;
+pub mod file_formats;
+
+pub use error::Error;
\ No newline at end of file
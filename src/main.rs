@@ -1,89 +1,315 @@
-use std::path::Path;
-
-use rollcage_file_parser::file_formats::{
-    self,
-    btp::{parse_btp_header, parse_textures},
-    gt::decompress,
-    identify_format,
-    idx::{parse_records, IdxRecord},
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::path::{Path, PathBuf};
+
+use rollcage_file_parser::{
+    archive::{Archive, Entry, ImgFile, Issue},
+    export::{
+        atlas::{atlas_to_json, build_atlas},
+        cobjects::{cobjects_to_json, cobjects_to_obj},
+        dds::rgba_to_dds,
+        gltf::gfxm_to_glb,
+        indexed_png::{indexed_png_to_texture, texture_to_indexed_png},
+        manifest::{manifest_to_json, ManifestEntry},
+        obj::{gfxm_to_mtl, gfxm_to_obj, mtl_textures},
+        palette::{
+            palette_to_act, palette_to_gpl, palette_to_jasc_pal, palette_users, palettes_to_json,
+        },
+        trace::{trace_to_json, trace_to_text},
+        verify::issues_to_json,
+    },
+    file_formats::{
+        self,
+        btp::{
+            parse_btp_header, parse_cobjects, parse_palettes, parse_texture_page_infos,
+            parse_texture_pages, parse_textures, rebuild_btp, Palette, Texture,
+        },
+        gfxm::{parse_gfxm, parse_gfxm_header},
+        gt::{decompress_with_overlap, parse_header as parse_gt_header, trace as trace_gt},
+        identify_format,
+        idx::Packer,
+        modl::{parse_modl, parse_modl_header},
+    },
+    Error,
 };
 
 extern crate clap;
-use clap::{crate_version, App, Arg};
+use image::RgbaImage;
 
-#[derive(Debug)]
-struct ImgEntry<'a> {
-    record_id: usize,
-    record: IdxRecord,
-    data: &'a [u8],
-    format: file_formats::Format,
-}
-
-impl ImgEntry<'_> {
-    pub fn save_to_file(&self, output_path: &Path) -> Result<(), String> {
-        let data = match self.format {
-            file_formats::Format::GT20 => {
-                match decompress(self.data, self.record.decompressed_file_length) {
-                    Ok(uncompressed_data) => uncompressed_data,
-                    Err(e) => {
-                        let err_msg = format!(
-                            "Could not uncompress image_file for record {}: {:?}",
-                            self.record_id, e
-                        );
-                        return Err(err_msg);
-                    }
-                }
-            }
-            _ => {
-                // assumed no compression
-                self.data.to_vec()
-            }
-        };
+use clap::{crate_version, App, AppSettings, Arg, ArgMatches, SubCommand};
+
+// How the extract command saves records.
+struct ExtractOptions<'a> {
+    output_path: &'a Path,
+    image_format: &'a str,
+    palettes: bool,
+}
 
-        let data_format = identify_format(&data);
-        let filename_stem = output_path.join(format!("output{}", self.record_id));
+// Problems that do not stop a record from being saved are added to `warnings`.
+fn save_entry(
+    archive: &Archive,
+    entry: &Entry,
+    data: &[u8],
+    options: &ExtractOptions,
+    files: &mut SavedFiles,
+    warnings: &mut Vec<String>,
+) -> Result<(), String> {
+    let data_format = identify_format(data);
+    let filename_stem = options.output_path.join(format!("output{}", entry.id));
 
-        if data_format == file_formats::Format::Btp {
-            self.save_png_from_btp(&data, &filename_stem);
+    let filename = filename_stem.with_extension(data_format.to_string());
+    files
+        .save_file(&filename, data)
+        .map_err(|e| format!("record {}: {}", entry.id, e))?;
+
+    let converted = match data_format {
+        file_formats::Format::Btp => {
+            // The cobjects are a debug dump, so a table that does not parse is only reported.
+            if let Err(e) = save_cobjects_from_btp(data, &filename_stem, files) {
+                warnings.push(format!("record {}: {}", entry.id, e));
+            }
+            save_images_from_btp(data, &filename_stem, options.image_format, files)
+                .and_then(|_| {
+                    save_atlas_from_btp(data, &filename_stem, options.image_format, files)
+                })
+                .and_then(|_| match options.palettes {
+                    true => save_palettes_from_btp(data, &filename_stem, files),
+                    false => Ok(()),
+                })
         }
+        file_formats::Format::Gfxm => {
+            let texture_pages = find_texture_pages(archive, entry.id, warnings);
+            save_models_from_gfxm(data, &filename_stem, &texture_pages, files)
+        }
+        _ => Ok(()),
+    };
+    converted.map_err(|e| format!("record {}: {}", entry.id, e))
+}
 
-        let filename = filename_stem.with_extension(data_format.to_string());
-        if std::fs::write(filename, data).is_err() {
-            println!("Couldn't save the file")
-        };
+fn save_file(filename: &Path, data: &[u8]) -> Result<(), String> {
+    std::fs::write(filename, data).map_err(|e| format!("Could not save {:?}: {}", filename, e))
+}
+
+// The files saved for a record, for the manifest.
+#[derive(Default)]
+struct SavedFiles(Vec<PathBuf>);
 
+impl SavedFiles {
+    fn save_file(&mut self, filename: &Path, data: &[u8]) -> Result<(), String> {
+        save_file(filename, data)?;
+        self.0.push(filename.to_path_buf());
         Ok(())
     }
 
-    fn save_png_from_btp(&self, data: &[u8], filename_stem: &Path) {
-        let btp_header = parse_btp_header(data).unwrap().1;
-        let textures = parse_textures(data, &btp_header).unwrap().1;
-        if !textures.is_empty() {
-            match std::fs::create_dir(filename_stem) {
-                Ok(_) => (),
-                Err(e) => {
-                    if e.kind() != std::io::ErrorKind::AlreadyExists {
-                        panic!(
-                            "Could not create output folder during btp conversion: {}",
-                            e
-                        );
-                    }
-                }
-            };
-        }
-        for (i, texture) in textures.iter().enumerate() {
-            let image = texture.to_rgba_image();
-            let filename_bitmap = filename_stem
-                .join(format!("image_{}", i))
-                .with_extension("bmp");
-            match image.save(&filename_bitmap) {
-                Ok(_) => (),
-                Err(e) => {
-                    println!("Could not save {:?}:{}", filename_bitmap, e);
-                }
+    fn save_image(&mut self, image: &RgbaImage, filename: &Path) -> Result<(), String> {
+        save_image(image, filename)?;
+        self.0.push(filename.to_path_buf());
+        Ok(())
+    }
+}
+
+// The folder a btp's images and palettes, or a gfxm's textures, are saved in.
+fn create_record_output_dir(filename_stem: &Path) -> Result<(), String> {
+    match std::fs::create_dir(filename_stem) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+        Err(e) => Err(format!("Could not create output folder {:?}: {}", filename_stem, e)),
+    }
+}
+
+// Save an image in the format given by its extension.
+fn save_image(image: &RgbaImage, filename: &Path) -> Result<(), String> {
+    match filename.extension().and_then(|extension| extension.to_str()) {
+        Some("dds") => save_file(filename, &rgba_to_dds(image)),
+        _ => image
+            .save(filename)
+            .map_err(|e| format!("Could not save {:?}: {}", filename, e)),
+    }
+}
+
+fn save_images_from_btp(
+    data: &[u8],
+    filename_stem: &Path,
+    image_format: &str,
+    files: &mut SavedFiles,
+) -> Result<(), String> {
+    let btp_header = parse_btp_header(data).map_err(|e| e.to_string())?;
+    let textures = parse_textures(data, &btp_header).map_err(|e| e.to_string())?;
+    if !textures.is_empty() {
+        create_record_output_dir(filename_stem)?;
+    }
+    for (i, texture) in textures.iter().enumerate() {
+        let filename_stem_image = filename_stem.join(format!("image_{}", i));
+        if image_format == "indexed-png" {
+            let filename_image = filename_stem_image.with_extension("png");
+            let png = texture_to_indexed_png(texture)
+                .map_err(|e| format!("Could not save {:?}: {}", filename_image, e))?;
+            files.save_file(&filename_image, &png)?;
+        } else {
+            let filename_image = filename_stem_image.with_extension(image_format);
+            files.save_image(&texture.to_rgba_image(), &filename_image)?;
+        }
+    }
+    Ok(())
+}
+
+// One image of every texture with its label, and a json file with each texture's rectangle in it.
+fn save_atlas_from_btp(
+    data: &[u8],
+    filename_stem: &Path,
+    image_format: &str,
+    files: &mut SavedFiles,
+) -> Result<(), String> {
+    let btp_header = parse_btp_header(data).map_err(|e| e.to_string())?;
+    let texture_pages = parse_texture_pages(data, &btp_header).map_err(|e| e.to_string())?;
+    if texture_pages.iter().all(|texture| texture.is_none()) {
+        return Ok(());
+    }
+
+    let (atlas, rects) = build_atlas(&texture_pages);
+    let extension = match image_format {
+        "indexed-png" => "png",
+        format => format,
+    };
+    let filename_atlas = filename_stem.with_extension(format!("atlas.{}", extension));
+    files.save_image(&atlas, &filename_atlas)?;
+    let filename_json = filename_stem.with_extension("atlas.json");
+    files.save_file(&filename_json, atlas_to_json(&atlas, &rects).as_bytes())
+}
+
+// Every palette as palette_N.act, .gpl and .pal, with palettes.json listing the texture pages
+// that use each one.
+fn save_palettes_from_btp(
+    data: &[u8],
+    filename_stem: &Path,
+    files: &mut SavedFiles,
+) -> Result<(), String> {
+    let btp_header = parse_btp_header(data).map_err(|e| e.to_string())?;
+    let palettes = parse_palettes(
+        data,
+        btp_header.palette_data_offset as usize,
+        btp_header.num_palettes as usize,
+    )
+    .map_err(|e| e.to_string())?;
+    let texture_page_infos = parse_texture_page_infos(
+        data,
+        btp_header.texture_page_table_offset as usize,
+        btp_header.num_textures as usize,
+    )
+    .map_err(|e| e.to_string())?;
+    if palettes.is_empty() {
+        return Ok(());
+    }
+    create_record_output_dir(filename_stem)?;
+
+    let users = palette_users(palettes.len(), &texture_page_infos);
+    for (i, (palette, texture_pages)) in palettes.iter().zip(users.iter()).enumerate() {
+        let filename_palette = filename_stem.join(format!("palette_{}", i));
+        let name = filename_palette.file_name().unwrap_or_default().to_string_lossy();
+        files.save_file(&filename_palette.with_extension("act"), &palette_to_act(palette))?;
+        let gpl = palette_to_gpl(palette, &name, texture_pages);
+        files.save_file(&filename_palette.with_extension("gpl"), gpl.as_bytes())?;
+        let pal = palette_to_jasc_pal(palette);
+        files.save_file(&filename_palette.with_extension("pal"), pal.as_bytes())?;
+    }
+    let json = palettes_to_json(&palettes, &texture_page_infos);
+    files.save_file(&filename_stem.join("palettes.json"), json.as_bytes())
+}
+
+fn save_cobjects_from_btp(
+    data: &[u8],
+    filename_stem: &Path,
+    files: &mut SavedFiles,
+) -> Result<(), String> {
+    let btp_header = parse_btp_header(data).map_err(|e| e.to_string())?;
+    let cobjects = parse_cobjects(data, &btp_header).map_err(|e| e.to_string())?;
+    if cobjects.is_empty() {
+        return Ok(());
+    }
+    let filename_json = filename_stem.with_extension("cobjects.json");
+    files.save_file(&filename_json, cobjects_to_json(&cobjects).as_bytes())?;
+    let filename_obj = filename_stem.with_extension("cobjects.obj");
+    files.save_file(&filename_obj, cobjects_to_obj(&cobjects).as_bytes())
+}
+
+// Which btp a gfxm's texture pages belong to is not known. As a guess, they are taken from the
+// closest btp record before it, which has not been checked against the game. Records that cannot
+// be read on the way, or a btp whose textures cannot be, are reported in `warnings`.
+fn find_texture_pages(
+    archive: &Archive,
+    record_id: usize,
+    warnings: &mut Vec<String>,
+) -> Vec<Option<Texture>> {
+    for entry in (0..record_id).rev().filter_map(|id| archive.get(id)) {
+        // Only the start of a record is decoded to find out whether it is a btp.
+        match entry.decoded_format() {
+            Ok(file_formats::Format::Btp) => {}
+            Ok(_) => continue,
+            Err(e) => {
+                warnings.push(format!(
+                    "record {}: skipped record {} while looking for its btp: {}",
+                    record_id, entry.id, e
+                ));
+                continue;
             }
         }
+        let texture_pages = entry.decoded_bytes().and_then(|data| {
+            let btp_header = parse_btp_header(&data)?;
+            parse_texture_pages(&data, &btp_header)
+        });
+        return texture_pages.unwrap_or_else(|e| {
+            warnings.push(format!(
+                "record {}: saved without textures, its btp could not be read: {}",
+                record_id,
+                e.with_entry(entry.id)
+            ));
+            Vec::new()
+        });
+    }
+    warnings.push(format!(
+        "record {}: saved without textures, there is no btp record before it",
+        record_id
+    ));
+    Vec::new()
+}
+
+fn save_models_from_gfxm(
+    data: &[u8],
+    filename_stem: &Path,
+    texture_pages: &[Option<Texture>],
+    files: &mut SavedFiles,
+) -> Result<(), String> {
+    let gfxm = parse_gfxm(data).map_err(|e| e.to_string())?;
+
+    let filename_obj = filename_stem.with_extension("obj");
+    let filename_mtl = filename_stem.with_extension("mtl");
+    let mtl_name = filename_mtl.file_name().unwrap_or_default().to_string_lossy();
+    files.save_file(&filename_obj, gfxm_to_obj(&gfxm, &mtl_name, texture_pages).as_bytes())?;
+
+    // The mtl refers to the textures by their path from it, in the gfxm's own folder.
+    let textures = mtl_textures(&gfxm, texture_pages);
+    if !textures.is_empty() {
+        create_record_output_dir(filename_stem)?;
     }
+    for (filename, texture) in textures {
+        files.save_image(&texture.to_rgba_image(), &filename_stem.join(filename))?;
+    }
+    let texture_dir = filename_stem.file_name().unwrap_or_default().to_string_lossy();
+    let mtl = gfxm_to_mtl(&gfxm, texture_pages, &texture_dir);
+    files.save_file(&filename_mtl, mtl.as_bytes())?;
+
+    let filename_glb = filename_stem.with_extension("glb");
+    let glb = gfxm_to_glb(&gfxm, texture_pages)
+        .map_err(|e| format!("Could not save {:?}: {}", filename_glb, e))?;
+    files.save_file(&filename_glb, &glb)
+}
+
+// Print the error and stop, for errors the tool cannot continue after.
+fn exit_with_error(message: String) -> ! {
+    eprintln!("{}. Cannot continue.", message);
+    std::process::exit(1);
 }
 
 // Check if the specified file exists.
@@ -95,13 +321,69 @@ fn is_file(val: String) -> Result<(), String> {
     }
 }
 
+// Check if the specified directory exists.
+fn is_dir(val: String) -> Result<(), String> {
+    if std::path::Path::is_dir(std::path::Path::new(&val)) {
+        Ok(())
+    } else {
+        Err(format!("Specified path is not a directory. {}", val))
+    }
+}
+
+// Check if the specified value is a record id.
+fn is_record_id(val: String) -> Result<(), String> {
+    match val.parse::<usize>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("Specified value is not a record id. {}", val)),
+    }
+}
+
+// Check if the specified value is a palette slot.
+fn is_palette_id(val: String) -> Result<(), String> {
+    match val.parse::<u32>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("Specified value is not a palette id. {}", val)),
+    }
+}
+
+// Check if the specified value is a usable number of worker threads.
+fn is_worker_count(val: String) -> Result<(), String> {
+    match val.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err(format!("Specified value is not a number of jobs above 0. {}", val)),
+    }
+}
+
+// Check if the specified value is a record id or an inclusive range of them, like 40-55.
+fn is_record_range(val: String) -> Result<(), String> {
+    match parse_record_range(&val) {
+        Some(_) => Ok(()),
+        None => Err(format!("Specified value is not a record id or range. {}", val)),
+    }
+}
+
+fn parse_record_range(val: &str) -> Option<RangeInclusive<usize>> {
+    let (start, end) = match val.split_once('-') {
+        Some((start, end)) => (start.trim().parse().ok()?, end.trim().parse().ok()?),
+        None => {
+            let id = val.trim().parse().ok()?;
+            (id, id)
+        }
+    };
+    if start <= end {
+        Some(start..=end)
+    } else {
+        None
+    }
+}
+
 // Check if the specified directory exists, if not attempt to create directory.
 fn validate_dir(val: String) -> Result<(), String> {
     let val = std::path::Path::new(&val);
     if std::path::Path::is_dir(val) {
         Ok(())
     } else {
-        match std::fs::create_dir(&val) {
+        match std::fs::create_dir(val) {
             Ok(_) => Ok(()),
             Err(e) => {
                 if e.kind() != std::io::ErrorKind::AlreadyExists {
@@ -114,27 +396,247 @@ fn validate_dir(val: String) -> Result<(), String> {
     }
 }
 
-fn main() {
-    let matches = App::new("RollCage Extractor")
-  .version(crate_version!())
-  .about("Extracts the contents of Rollcage's IDXData folder. Contents mainly include game textures, models and tracks.")
-  .arg(Arg::with_name("idxFile")
-    .help("The idx file to use.")
-    .required(true)
-    .index(1)
-    .validator(is_file))
-  .arg(Arg::with_name("imgFile")
-    .help("The img file to use. Default assumes img file is located in the directory as the idx file.")
-    .required(false)
-    .validator(is_file))
-  .arg(Arg::with_name("output")
-    .help("Set the output directory of the extracted files")
-    .short("o")
-    .long("output")
-    .value_name("path")
-    .validator(validate_dir))
-  .get_matches();
+// Find the outputN files written by extraction, ordered by record id.
+fn collect_extracted_files(input_path: &Path) -> Result<Vec<PathBuf>, String> {
+    let record_extensions: Vec<String> = [
+        file_formats::Format::Btp,
+        file_formats::Format::Bitmap,
+        file_formats::Format::Gfxm,
+        file_formats::Format::Modl,
+        file_formats::Format::GT20,
+        file_formats::Format::Unknown,
+    ]
+    .iter()
+    .map(|format| format.to_string())
+    .collect();
+
+    let dir = match std::fs::read_dir(input_path) {
+        Ok(dir) => dir,
+        Err(e) => return Err(format!("Could not read {:?}: {}", input_path, e)),
+    };
+
+    let mut files: BTreeMap<usize, PathBuf> = BTreeMap::new();
+    for dir_entry in dir {
+        let path = match dir_entry {
+            Ok(dir_entry) => dir_entry.path(),
+            Err(e) => return Err(format!("Could not read {:?}: {}", input_path, e)),
+        };
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        if !path.is_file() || !record_extensions.iter().any(|e| e == extension) {
+            continue;
+        }
+        let record_id = match path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.strip_prefix("output"))
+            .and_then(|id| id.parse::<usize>().ok())
+        {
+            Some(record_id) => record_id,
+            None => continue,
+        };
+        if let Some(existing) = files.insert(record_id, path.clone()) {
+            return Err(format!(
+                "Found more than one file for record {}: {:?} and {:?}",
+                record_id, existing, path
+            ));
+        }
+    }
+
+    for (expected_id, record_id) in files.keys().enumerate() {
+        if expected_id != *record_id {
+            return Err(format!("Missing file for record {}", expected_id));
+        }
+    }
+
+    Ok(files.into_values().collect())
+}
+
+fn pack(matches: &ArgMatches) {
+    let input_path = Path::new(matches.value_of("inputDir").unwrap());
+    let idx_path = Path::new(matches.value_of("idxFile").unwrap());
+    let img_pathbuf = match matches.value_of("imgFile") {
+        Some(v) => PathBuf::from(v),
+        None => idx_path.with_extension("img"),
+    };
+    let img_path = img_pathbuf.as_path();
+
+    let compress_all = matches.is_present("compressAll");
+    let compress_ids: Vec<usize> = match matches.values_of("compress") {
+        Some(values) => values.map(|v| v.parse().unwrap()).collect(),
+        None => Vec::new(),
+    };
+
+    let files = match collect_extracted_files(input_path) {
+        Ok(files) => files,
+        Err(e) => exit_with_error(e),
+    };
+    if let Some(record_id) = compress_ids.iter().find(|id| **id >= files.len()) {
+        exit_with_error(format!(
+            "There is no record {} to compress, the folder has {} records",
+            record_id,
+            files.len()
+        ));
+    }
+
+    let mut packer = Packer::new();
+    for (record_id, file) in files.iter().enumerate() {
+        let data = match std::fs::read(file) {
+            Ok(data) => data,
+            Err(e) => exit_with_error(format!("Failed to read {:?}: {}", file, e)),
+        };
+        if let Err(e) = packer.add(&data, compress_all || compress_ids.contains(&record_id)) {
+            exit_with_error(format!("Failed to pack {:?}: {}", file, e));
+        }
+        print!("\rPacking: [{}/{}] ", record_id + 1, files.len());
+    }
+
+    let (idx, img) = packer.finish();
+    if let Err(e) = std::fs::write(idx_path, idx) {
+        exit_with_error(format!("Failed to write {:?}: {}", idx_path, e));
+    }
+    if let Err(e) = std::fs::write(img_path, img) {
+        exit_with_error(format!("Failed to write {:?}: {}", img_path, e));
+    }
+    println!("\nDone!");
+}
+
+// Images a btp was extracted to, in the formats extraction can write.
+const TEXTURE_IMAGE_EXTENSIONS: [&str; 3] = ["png", "bmp", "tga"];
+
+// Replaces the texture pages of a btp with the images found in a folder. image_N is the Nth texture
+// page that has an image, the same numbering extraction uses. Pages without an image file are kept.
+// Paletted pngs keep their indices and replace the palette of their page's slot with their own,
+// which `rebuild_btp` only allows when every page sharing the slot gets the same palette.
+// When quantizing, each palette slot with an edited page gets a new palette built from every page
+// that shares it, apart from the slots in `kept_palettes`. Otherwise images are mapped to the
+// existing palettes.
+fn rebuild_btp_from_images(
+    btp_data: &[u8],
+    image_dir: &Path,
+    quantize: bool,
+    kept_palettes: &[u32],
+) -> Result<Vec<u8>, String> {
+    let btp_header = parse_btp_header(btp_data).map_err(|e| e.to_string())?;
+    let texture_pages = parse_texture_pages(btp_data, &btp_header).map_err(|e| e.to_string())?;
+
+    // Edited pages are either true colour images or paletted pngs, which are used as they are.
+    let mut images: Vec<Option<RgbaImage>> = vec![None; texture_pages.len()];
+    let mut indexed_textures: Vec<Option<Texture>> = vec![None; texture_pages.len()];
+    let textures = texture_pages
+        .iter()
+        .enumerate()
+        .filter_map(|(page, texture)| texture.as_ref().map(|texture| (page, texture)));
+    for (i, (page, texture)) in textures.enumerate() {
+        let image_path = TEXTURE_IMAGE_EXTENSIONS
+            .iter()
+            .map(|extension| image_dir.join(format!("image_{}", i)).with_extension(extension))
+            .find(|path| path.is_file());
+        let image_path = match image_path {
+            Some(path) => path,
+            None => continue,
+        };
+        let image_data = std::fs::read(&image_path)
+            .map_err(|e| format!("Could not read {:?}: {}", image_path, e))?;
+
+        let indexed = match image_path.extension().and_then(|extension| extension.to_str()) {
+            Some("png") => indexed_png_to_texture(texture.info, &image_data)
+                .map_err(|e| format!("Could not read {:?}: {}", image_path, e))?,
+            _ => None,
+        };
+        let size = match &indexed {
+            Some(indexed) => (indexed.info.width as u32, indexed.info.height as u32),
+            None => {
+                let image = image::load_from_memory(&image_data)
+                    .map_err(|e| format!("Could not read {:?}: {}", image_path, e))?
+                    .to_rgba8();
+                let size = image.dimensions();
+                images[page] = Some(image);
+                size
+            }
+        };
+        if size != (texture.info.width as u32, texture.info.height as u32) {
+            return Err(format!(
+                "{:?} is {}x{}, the texture page it replaces is {}x{}",
+                image_path, size.0, size.1, texture.info.width, texture.info.height
+            ));
+        }
+        indexed_textures[page] = indexed;
+    }
+
+    // Palette slots that get a new palette, with the pages that share each of them.
+    let mut quantized_slots: BTreeMap<u32, Vec<usize>> = BTreeMap::new();
+    if quantize {
+        for (page, texture) in texture_pages.iter().enumerate() {
+            if let Some(texture) = texture {
+                if !kept_palettes.contains(&texture.info.palette) {
+                    quantized_slots.entry(texture.info.palette).or_default().push(page);
+                }
+            }
+        }
+        quantized_slots.retain(|_, pages| pages.iter().any(|page| images[*page].is_some()));
+    }
+
+    let mut edited_pages: Vec<Option<Texture>> = vec![None; texture_pages.len()];
+    for pages in quantized_slots.values() {
+        let slot_images: Vec<RgbaImage> = pages
+            .iter()
+            .map(|page| match (&images[*page], &indexed_textures[*page]) {
+                (Some(image), _) => image.clone(),
+                (None, Some(indexed)) => indexed.to_rgba_image(),
+                (None, None) => texture_pages[*page].as_ref().unwrap().to_rgba_image(),
+            })
+            .collect();
+        let palette = Palette::quantize(&slot_images.iter().collect::<Vec<_>>());
+        for (page, image) in pages.iter().zip(slot_images.iter()) {
+            let info = texture_pages[*page].as_ref().unwrap().info;
+            let edited = Texture::from_rgba_image(info, palette, image)
+                .map_err(|e| format!("Could not convert texture page {}: {}", page, e))?;
+            edited_pages[*page] = Some(edited);
+        }
+    }
+    for (page, indexed) in indexed_textures.into_iter().enumerate() {
+        if edited_pages[page].is_none() {
+            edited_pages[page] = indexed;
+        }
+    }
+    for (page, image) in images.iter().enumerate() {
+        if let (Some(image), None) = (image, &edited_pages[page]) {
+            let texture = texture_pages[page].as_ref().unwrap();
+            let edited = Texture::from_rgba_image(texture.info, texture.palette, image)
+                .map_err(|e| format!("Could not convert texture page {}: {}", page, e))?;
+            edited_pages[page] = Some(edited);
+        }
+    }
 
+    rebuild_btp(btp_data, &edited_pages).map_err(|e| e.to_string())
+}
+
+fn rebuild_btp_command(matches: &ArgMatches) {
+    let btp_path = Path::new(matches.value_of("btpFile").unwrap());
+    let image_dir = Path::new(matches.value_of("imageDir").unwrap());
+    let output_path = Path::new(matches.value_of("outputFile").unwrap());
+
+    let btp_data = match std::fs::read(btp_path) {
+        Ok(data) => data,
+        Err(e) => exit_with_error(format!("Failed to read {:?}: {}", btp_path, e)),
+    };
+    let kept_palettes: Vec<u32> = matches
+        .values_of("keepPalette")
+        .map(|values| values.map(|value| value.parse().unwrap()).collect())
+        .unwrap_or_default();
+    let rebuilt = rebuild_btp_from_images(
+        &btp_data,
+        image_dir,
+        matches.is_present("quantize"),
+        &kept_palettes,
+    )
+    .unwrap_or_else(|e| exit_with_error(e));
+    save_file(output_path, &rebuilt).unwrap_or_else(|e| exit_with_error(e));
+    println!("Done!");
+}
+
+// Read the idx file and its img file. The img file defaults to the one next to the idx file.
+fn read_archive_files(matches: &ArgMatches) -> (Vec<u8>, ImgFile) {
     let idx_path = std::path::Path::new(matches.value_of("idxFile").unwrap());
 
     let img_pathbuf = match matches.value_of("imgFile") {
@@ -144,60 +646,519 @@ fn main() {
             if out.is_file() {
                 out
             } else {
-                let panic_message = format!(
+                exit_with_error(format!(
                     "Could not find a file with the path: {}",
-                    out.to_str().unwrap()
-                );
-                panic!("{}", panic_message);
+                    out.display()
+                ));
             }
         }
     };
-
     let img_path = img_pathbuf.as_path();
-    let output = matches.value_of("output").unwrap_or(".");
-    let output_path = std::path::Path::new(output);
 
-    let img = match std::fs::read(img_path) {
-        Ok(data) => data,
-        Err(e) => {
-            panic!("Failed to read {:?}: {}. Cannot continue.", img_path, e);
-        }
+    let img = match ImgFile::open(img_path) {
+        Ok(img) => img,
+        Err(e) => exit_with_error(format!("Failed to read {:?}: {}", img_path, e)),
     };
     let idx = match std::fs::read(idx_path) {
         Ok(data) => data,
-        Err(e) => {
-            panic!("Failed to read {:?}: {}. Cannot continue.", idx_path, e);
+        Err(e) => exit_with_error(format!("Failed to read {:?}: {}", idx_path, e)),
+    };
+
+    (idx, img)
+}
+
+fn open_archive<'a>(idx: &[u8], img: &'a ImgFile) -> Archive<'a> {
+    match Archive::open(idx, img.bytes()) {
+        Ok(archive) => archive,
+        Err(e) => exit_with_error(format!("Failed to read the idx file: {}", e)),
+    }
+}
+
+fn format_name(format: file_formats::Format) -> String {
+    match format {
+        file_formats::Format::Unknown => String::from("unknown"),
+        format => format.to_string(),
+    }
+}
+
+fn list(matches: &ArgMatches) {
+    let (idx, img) = read_archive_files(matches);
+    let archive = open_archive(&idx, &img);
+
+    println!(
+        "{:>6} {:>10} {:>10} {:>12}  format",
+        "id", "offset", "compressed", "decompressed"
+    );
+    for entry in archive.entries() {
+        // Compressed records also show the format of their decompressed contents, which only
+        // needs the start of them decompressed.
+        let format = match (entry.raw_bytes(), entry.format()) {
+            (Err(_), _) => String::from("out of bounds"),
+            (Ok(_), file_formats::Format::GT20) => match entry.decoded_format() {
+                Ok(format) => format!("gt20 ({})", format_name(format)),
+                Err(_) => String::from("gt20 (corrupt)"),
+            },
+            (Ok(_), format) => format_name(format),
+        };
+        println!(
+            "{:>6} {:>10} {:>10} {:>12}  {}",
+            entry.id,
+            entry.record.file_offset,
+            entry.record.compressed_file_length,
+            entry.record.decompressed_file_length,
+            format
+        );
+    }
+}
+
+fn info(matches: &ArgMatches) {
+    let (idx, img) = read_archive_files(matches);
+    let archive = open_archive(&idx, &img);
+
+    let record_id: usize = matches.value_of("id").unwrap().parse().unwrap();
+    let entry = match archive.get(record_id) {
+        Some(entry) => entry,
+        None => exit_with_error(format!(
+            "There is no record {}, the idx file has {} records",
+            record_id,
+            archive.len()
+        )),
+    };
+    println!("Record {}: {:#?}", entry.id, entry.record);
+
+    let raw_data = entry.raw_bytes().unwrap_or_else(|e| exit_with_error(e.to_string()));
+    if entry.format() == file_formats::Format::GT20 {
+        match parse_gt_header(raw_data) {
+            Ok(header) => println!("{:#?}", header),
+            Err(e) => exit_with_error(e.to_string()),
         }
+    }
+
+    let data = entry.decoded_bytes().unwrap_or_else(|e| exit_with_error(e.to_string()));
+    let header = match identify_format(&data) {
+        file_formats::Format::Btp => parse_btp_header(&data).map(|header| format!("{:#?}", header)),
+        file_formats::Format::Gfxm => parse_gfxm_header(&data).map(|header| format!("{:#?}", header)),
+        file_formats::Format::Modl => parse_modl_header(&data).map(|header| format!("{:#?}", header)),
+        file_formats::Format::Bitmap => Ok(String::from("Windows bitmap")),
+        _ => Ok(String::from("Unknown format")),
     };
+    match header {
+        Ok(header) => println!("{}", header),
+        Err(e) => exit_with_error(e.with_entry(entry.id).to_string()),
+    }
+}
 
-    let records = parse_records(&idx).unwrap().1;
+fn trace_command(matches: &ArgMatches) {
+    let (idx, img) = read_archive_files(matches);
+    let archive = open_archive(&idx, &img);
 
-    let mut img_entries: Vec<ImgEntry> = Vec::new();
-    for (record_id, record) in records.iter().enumerate() {
-        let record_data = img
-            .get(
-                record.file_offset as usize
-                    ..(record.file_offset + record.compressed_file_length) as usize,
-            )
-            .unwrap();
-        let format = identify_format(record_data);
-        let img_entry = ImgEntry {
+    let record_id: usize = matches.value_of("id").unwrap().parse().unwrap();
+    let entry = match archive.get(record_id) {
+        Some(entry) => entry,
+        None => exit_with_error(format!(
+            "There is no record {}, the idx file has {} records",
             record_id,
-            record: *record,
-            data: record_data,
-            format,
+            archive.len()
+        )),
+    };
+    let raw_data = entry.raw_bytes().unwrap_or_else(|e| exit_with_error(e.to_string()));
+    if entry.format() != file_formats::Format::GT20 {
+        exit_with_error(format!("Record {} is not GT20 compressed", record_id));
+    }
+
+    let trace = trace_gt(raw_data, entry.record.decompressed_file_length)
+        .unwrap_or_else(|e| exit_with_error(e.with_entry(entry.id).to_string()));
+    if matches.is_present("json") {
+        println!("{}", trace_to_json(&trace));
+    } else {
+        print!("{}", trace_to_text(&trace));
+    }
+}
+
+// Decode the entry and parse it fully if it is in a format the parser understands.
+fn verify_entry(entry: &Entry) -> Result<(), Error> {
+    let data = entry.decoded_bytes()?;
+    let parsed = match identify_format(&data) {
+        file_formats::Format::Btp => {
+            parse_btp_header(&data).and_then(|header| parse_textures(&data, &header).map(|_| ()))
+        }
+        file_formats::Format::Gfxm => parse_gfxm(&data).map(|_| ()),
+        file_formats::Format::Modl => parse_modl(&data, 0, 0).map(|_| ()),
+        _ => Ok(()),
+    };
+    parsed.map_err(|e| e.with_entry(entry.id))
+}
+
+// The game is thought to decompress GT20 records in place, which only works if the header's
+// overlap is large enough.
+fn verify_in_place(entry: &Entry) -> Option<Issue> {
+    let data = entry.raw_bytes().ok()?;
+    if entry.format() != file_formats::Format::GT20 {
+        return None;
+    }
+    let decompressed = decompress_with_overlap(data, entry.record.decompressed_file_length);
+    decompressed.err().map(|error| Issue::InPlaceFailure {
+        entry: entry.id,
+        error,
+    })
+}
+
+fn verify(matches: &ArgMatches) {
+    let (idx, img) = read_archive_files(matches);
+    let archive = open_archive(&idx, &img);
+
+    let mut issues = archive.check_records();
+    for entry in archive.entries() {
+        // Records with a layout or length error are already reported, and their lengths cannot be
+        // trusted to decode them.
+        if issues
+            .iter()
+            .any(|issue| issue.entry() == entry.id && issue.is_error())
+        {
+            continue;
+        }
+        match verify_entry(&entry) {
+            Ok(()) => issues.extend(verify_in_place(&entry)),
+            Err(error) => issues.push(Issue::Invalid {
+                entry: entry.id,
+                error,
+            }),
+        }
+    }
+    issues.sort_by_key(Issue::entry);
+
+    let mut failed: Vec<usize> = issues
+        .iter()
+        .filter(|issue| issue.is_error())
+        .map(Issue::entry)
+        .collect();
+    failed.dedup();
+
+    if matches.is_present("json") {
+        println!("{}", issues_to_json(archive.len(), &issues));
+    } else {
+        for issue in issues.iter() {
+            if issue.is_error() {
+                println!("{}", issue);
+            } else {
+                println!("warning: {}", issue);
+            }
+        }
+        println!("Checked {} records, {} failed.", archive.len(), failed.len());
+    }
+
+    if !failed.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+// A saved file's path in the manifest, relative to the output folder and with / separators.
+fn manifest_path(output_path: &Path, file: &Path) -> String {
+    let relative = file.strip_prefix(output_path).unwrap_or(file);
+    let components: Vec<_> = relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect();
+    components.join("/")
+}
+
+// Which records the extract command saves.
+struct ExtractFilter {
+    ids: Option<Vec<RangeInclusive<usize>>>,
+    formats: Option<Vec<String>>,
+    compressed_only: bool,
+}
+
+impl ExtractFilter {
+    fn from_matches(matches: &ArgMatches) -> Self {
+        ExtractFilter {
+            ids: matches
+                .values_of("id")
+                .map(|values| values.filter_map(parse_record_range).collect()),
+            formats: matches
+                .values_of("format")
+                .map(|values| values.map(String::from).collect()),
+            compressed_only: matches.is_present("compressedOnly"),
+        }
+    }
+
+    // Filters that only need the idx record and the stored bytes.
+    fn selects_record(&self, entry: &Entry) -> bool {
+        let id_selected = match &self.ids {
+            Some(ids) => ids.iter().any(|range| range.contains(&entry.id)),
+            None => true,
         };
-        img_entries.push(img_entry);
+        id_selected && (!self.compressed_only || entry.format() == file_formats::Format::GT20)
     }
 
-    for (i, img_entry) in img_entries.iter().enumerate() {
-        match img_entry.save_to_file(output_path) {
-            Ok(_) => {}
-            Err(e) => {
-                println!("{}", e)
+    // The format filter applies to the decompressed contents.
+    fn selects_format(&self, format: file_formats::Format) -> bool {
+        match &self.formats {
+            Some(formats) => formats.iter().any(|f| *f == format.to_string()),
+            None => true,
+        }
+    }
+}
+
+// The result of saving a record, the warnings it gave and its manifest entry.
+type ExtractedEntry = (Result<(), String>, Vec<String>, Option<ManifestEntry>);
+
+// Saves a record if its format is selected and returns its manifest entry. Records of other
+// formats are left out of the manifest.
+fn extract_entry(
+    archive: &Archive,
+    entry: &Entry,
+    filter: &ExtractFilter,
+    options: &ExtractOptions,
+) -> ExtractedEntry {
+    let data = match entry.decoded_bytes() {
+        Ok(data) if !filter.selects_format(identify_format(&data)) => {
+            return (Ok(()), Vec::new(), None)
+        }
+        data => data,
+    };
+
+    let mut manifest_entry = ManifestEntry::new(entry.id, entry.record, entry.format());
+    let mut files = SavedFiles::default();
+    let mut warnings = Vec::new();
+    let saved = data.map_err(|e| e.to_string()).and_then(|data| {
+        manifest_entry.set_data(&data);
+        save_entry(archive, entry, &data, options, &mut files, &mut warnings)
+    });
+    manifest_entry.error = saved.as_ref().err().cloned();
+    manifest_entry.files = files
+        .0
+        .iter()
+        .map(|file| manifest_path(options.output_path, file))
+        .collect();
+    (saved, warnings, Some(manifest_entry))
+}
+
+fn extract(matches: &ArgMatches) {
+    let (idx, img) = read_archive_files(matches);
+    let archive = open_archive(&idx, &img);
+
+    let output = matches.value_of("output").unwrap_or(".");
+    let options = ExtractOptions {
+        output_path: std::path::Path::new(output),
+        image_format: matches.value_of("imageFormat").unwrap(),
+        palettes: matches.is_present("palettes"),
+    };
+
+    let filter = ExtractFilter::from_matches(matches);
+    let entries: Vec<Entry> = archive
+        .entries()
+        .filter(|entry| filter.selects_record(entry))
+        .collect();
+
+    let num_workers = match matches.value_of("jobs") {
+        Some(jobs) => jobs.parse().unwrap(),
+        None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+
+    // Workers take the next record from a shared index and send back its result. Results are
+    // printed in record order so the output does not depend on the number of workers.
+    let next_entry = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+    let mut manifest = Vec::new();
+    std::thread::scope(|scope| {
+        for _ in 0..num_workers.min(entries.len()) {
+            let sender = sender.clone();
+            let (archive, entries, filter, next_entry, options) =
+                (&archive, &entries, &filter, &next_entry, &options);
+            scope.spawn(move || loop {
+                let i = next_entry.fetch_add(1, Ordering::Relaxed);
+                let entry = match entries.get(i) {
+                    Some(entry) => entry,
+                    None => break,
+                };
+                let extracted = extract_entry(archive, entry, filter, options);
+                if sender.send((i, extracted)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        let mut pending = BTreeMap::new();
+        let mut num_done = 0;
+        for (i, extracted) in receiver {
+            pending.insert(i, extracted);
+            while let Some((saved, warnings, manifest_entry)) = pending.remove(&num_done) {
+                for warning in warnings {
+                    println!("warning: {}", warning)
+                }
+                if let Err(e) = saved {
+                    println!("{}", e)
+                }
+                manifest.extend(manifest_entry);
+                num_done += 1;
+                print!("\rExtracting: [{}/{}] ", num_done, entries.len());
             }
         }
-        print!("\rExtracting: [{}/{}] ", i + 1, img_entries.len());
+    });
+
+    let filename_manifest = options.output_path.join("manifest.json");
+    if let Err(e) = save_file(&filename_manifest, manifest_to_json(&manifest).as_bytes()) {
+        println!("\n{}", e);
     }
     println!("\nDone!");
-}
\ No newline at end of file
+}
+
+fn idx_file_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("idxFile")
+        .help("The idx file to use.")
+        .required(true)
+        .validator(is_file)
+}
+
+fn img_file_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("imgFile")
+        .help("The img file to use. Default assumes img file is located in the directory as the idx file.")
+        .required(false)
+        .validator(is_file)
+}
+
+fn main() {
+    let matches = App::new("RollCage Extractor")
+  .version(crate_version!())
+  .about("Extracts the contents of Rollcage's IDXData folder. Contents mainly include game textures, models and tracks.")
+  .setting(AppSettings::SubcommandRequiredElseHelp)
+  .subcommand(SubCommand::with_name("list")
+    .about("Lists the records of an idx file with their offsets, lengths and formats.")
+    .arg(idx_file_arg().index(1))
+    .arg(img_file_arg().index(2)))
+  .subcommand(SubCommand::with_name("info")
+    .about("Shows the parsed headers of a record.")
+    .arg(idx_file_arg().index(1))
+    .arg(Arg::with_name("id")
+      .help("The id of the record to show.")
+      .required(true)
+      .index(2)
+      .validator(is_record_id))
+    .arg(img_file_arg().index(3)))
+  .subcommand(SubCommand::with_name("trace")
+    .about("Lists every token of a GT20 compressed record with its input and output offsets.")
+    .arg(idx_file_arg().index(1))
+    .arg(Arg::with_name("id")
+      .help("The id of the record to trace.")
+      .required(true)
+      .index(2)
+      .validator(is_record_id))
+    .arg(img_file_arg().index(3))
+    .arg(Arg::with_name("json")
+      .help("Print the trace as json.")
+      .long("json")))
+  .subcommand(SubCommand::with_name("extract")
+    .about("Extracts every record of an idx file.")
+    .arg(idx_file_arg().index(1))
+    .arg(img_file_arg().index(2))
+    .arg(Arg::with_name("output")
+      .help("Set the output directory of the extracted files")
+      .short("o")
+      .long("output")
+      .value_name("path")
+      .validator(validate_dir))
+    .arg(Arg::with_name("imageFormat")
+      .help("Set the file format of the images generated from btp textures. Every format keeps the palette alpha, but many programs ignore the alpha of bmp files. indexed-png writes 8 bit paletted pngs holding the btp palette and indices.")
+      .long("image-format")
+      .value_name("format")
+      .possible_values(&["png", "indexed-png", "bmp", "tga", "dds"])
+      .default_value("png"))
+    .arg(Arg::with_name("palettes")
+      .help("Also save every btp palette as act, gpl and pal files, with a palettes.json listing the texture pages that use each one.")
+      .long("palettes"))
+    .arg(Arg::with_name("id")
+      .help("Only extract the records with these ids or id ranges, separated by commas. For example 12,40-55.")
+      .long("id")
+      .value_name("ids")
+      .use_delimiter(true)
+      .validator(is_record_range))
+    .arg(Arg::with_name("format")
+      .help("Only extract the records whose decompressed contents have these formats, separated by commas.")
+      .long("format")
+      .value_name("formats")
+      .use_delimiter(true)
+      .possible_values(&["btp", "bmp", "gfxm", "modl"]))
+    .arg(Arg::with_name("jobs")
+      .help("The number of records to extract at the same time. Default uses one per CPU core.")
+      .short("j")
+      .long("jobs")
+      .value_name("n")
+      .validator(is_worker_count))
+    .arg(Arg::with_name("compressedOnly")
+      .help("Only extract the records that are GT20 compressed.")
+      .long("compressed-only")))
+  .subcommand(SubCommand::with_name("pack")
+    .about("Packs a folder of extracted outputN files into a new idx and img file.")
+    .arg(Arg::with_name("inputDir")
+      .help("The folder containing the outputN files to pack.")
+      .required(true)
+      .index(1)
+      .validator(is_dir))
+    .arg(Arg::with_name("idxFile")
+      .help("The idx file to write.")
+      .required(true)
+      .index(2))
+    .arg(Arg::with_name("imgFile")
+      .help("The img file to write. Default writes the img file next to the idx file.")
+      .required(false)
+      .index(3))
+    .arg(Arg::with_name("compress")
+      .help("GT20 compress the records with these ids, separated by commas.")
+      .short("c")
+      .long("compress")
+      .value_name("ids")
+      .use_delimiter(true)
+      .validator(is_record_id))
+    .arg(Arg::with_name("compressAll")
+      .help("GT20 compress every record.")
+      .long("compress-all")
+      .conflicts_with("compress")))
+  .subcommand(SubCommand::with_name("rebuild-btp")
+    .about("Writes a copy of a btp file with its textures replaced by edited images.")
+    .arg(Arg::with_name("btpFile")
+      .help("The btp file to rebuild.")
+      .required(true)
+      .index(1)
+      .validator(is_file))
+    .arg(Arg::with_name("imageDir")
+      .help("The folder of image_N files the btp was extracted to. Textures without an image are kept.")
+      .required(true)
+      .index(2)
+      .validator(is_dir))
+    .arg(Arg::with_name("outputFile")
+      .help("The btp file to write.")
+      .required(true)
+      .index(3))
+    .arg(Arg::with_name("quantize")
+      .help("Build new 256 colour palettes for the edited images. Default maps their colours to the closest colour of the existing palettes.")
+      .short("q")
+      .long("quantize"))
+    .arg(Arg::with_name("keepPalette")
+      .help("Palette slots to keep as they are when quantizing, separated by commas. Textures using them are mapped to the existing palette.")
+      .long("keep-palette")
+      .value_name("ids")
+      .use_delimiter(true)
+      .requires("quantize")
+      .validator(is_palette_id)))
+  .subcommand(SubCommand::with_name("verify")
+    .about("Checks the layout and lengths of every record of an idx file, decodes and parses them and reports the ones that fail.")
+    .arg(idx_file_arg().index(1))
+    .arg(img_file_arg().index(2))
+    .arg(Arg::with_name("json")
+      .help("Print the report as json.")
+      .long("json")))
+  .get_matches();
+
+    match matches.subcommand() {
+        ("list", Some(list_matches)) => list(list_matches),
+        ("info", Some(info_matches)) => info(info_matches),
+        ("trace", Some(trace_matches)) => trace_command(trace_matches),
+        ("extract", Some(extract_matches)) => extract(extract_matches),
+        ("pack", Some(pack_matches)) => pack(pack_matches),
+        ("rebuild-btp", Some(rebuild_matches)) => rebuild_btp_command(rebuild_matches),
+        ("verify", Some(verify_matches)) => verify(verify_matches),
+        _ => unreachable!(),
+    }
+}